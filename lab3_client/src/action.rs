@@ -1,37 +1,27 @@
+use protocol::{AccountRevision, AuditEntry, AuditQuery, Request, Response, UserRole, ANONYMOUS};
 use read_input::prelude::*;
-use serde::{Deserialize, Serialize};
 /// This file is used to execute the various actions sent to the server
 ///
 /// Tasks todo: - Some client-side input/output validation
 use std::error::Error;
 use strum::IntoEnumIterator;
 use strum_macros::{Display, EnumIter, EnumString};
+use time::format_description::FormatItem;
+use time::macros::format_description;
+use time::{Date, OffsetDateTime};
 use utils::ErrorMessage;
-use validation::{Password, PhoneNumber, Username};
+use validation::{AuthCode, Password, PhoneNumber, Username};
 
 use crate::connection::Connection;
 
-type EmptyResult = Result<(), ErrorMessage>;
+const DATE_FORMAT: &[FormatItem] = format_description!("[year]-[month]-[day]");
+const TIME_FORMAT: &[FormatItem] =
+    format_description!("[year]-[month]-[day] [hour]:[minute]:[second]");
 
-#[derive(Serialize, Deserialize, Clone, Debug)]
-struct UserAccount {
-    username: Username,
-    password: Password,
-    phone_number: PhoneNumber,
-    role: UserRole,
-}
-
-#[derive(Serialize, Deserialize, Clone, Debug, Display, EnumString, EnumIter)]
-enum UserRole {
-    #[strum(serialize = "anon")]
-    Anon,
-    #[strum(serialize = "standard_user")]
-    StandardUser,
-    #[strum(serialize = "hr")]
-    HR,
-}
+/// Token of the current session, kept to resume it after a reconnection
+pub type Session = Option<String>;
 
-#[derive(Serialize, Deserialize, Display, EnumString, EnumIter)]
+#[derive(Display, EnumString, EnumIter)]
 pub enum Action {
     #[strum(serialize = "Show users", serialize = "1")]
     ShowUsers,
@@ -45,8 +35,33 @@ pub enum Action {
     Login,
     #[strum(serialize = "Logout", serialize = "6")]
     Logout,
-    #[strum(serialize = "Exit", serialize = "7")]
+    #[strum(serialize = "Unlock user", serialize = "7")]
+    UnlockUser,
+    #[strum(serialize = "Set up two-factor authentication", serialize = "8")]
+    EnableTwoFactor,
+    #[strum(serialize = "Change my password", serialize = "9")]
+    ChangeOwnPassword,
+    #[strum(serialize = "Reset someone's password", serialize = "10")]
+    ResetPassword,
+    #[strum(serialize = "Deactivate user", serialize = "11")]
+    DeactivateUser,
+    #[strum(serialize = "Reactivate user", serialize = "12")]
+    ReactivateUser,
+    #[strum(serialize = "Delete user", serialize = "13")]
+    DeleteUser,
+    #[strum(serialize = "Change someone's role", serialize = "14")]
+    ChangeRole,
+    #[strum(serialize = "Show audit log", serialize = "15")]
+    ShowAuditLog,
+    #[strum(serialize = "Show someone's change history", serialize = "16")]
+    ShowUserHistory,
+    #[strum(serialize = "Revert a change", serialize = "17")]
+    RevertChange,
+    #[strum(serialize = "Exit", serialize = "18")]
     Exit,
+    // Performed on its own after reconnecting, not listed in the menu
+    #[strum(disabled)]
+    ResumeSession,
 }
 
 impl Action {
@@ -57,69 +72,109 @@ impl Action {
         }
     }
 
-    pub fn perform(&self, connection: &mut Connection) -> Result<(), Box<dyn Error>> {
-        connection.send(self)?;
-
-        let res = match self {
-            Action::ShowUsers => Action::show_users(connection),
-            Action::ChangeOwnPhone => Action::change_own_phone(connection),
-            Action::ChangePhone => Action::change_phone(connection),
-            Action::AddUser => Action::add_user(connection),
-            Action::Login => Action::login(connection),
-            Action::Logout => Action::logout(connection),
-            Action::Exit => Err("Client disconnected")?,
+    /// Reads the inputs of the action, sends its request and shows the
+    /// response
+    pub fn perform(
+        &self,
+        connection: &mut Connection,
+        session: &mut Session,
+    ) -> Result<(), Box<dyn Error>> {
+        let mut client = Client {
+            connection,
+            session,
         };
-
-        res
+        match self {
+            Action::ShowUsers => Action::show_users(&mut client),
+            Action::ChangeOwnPhone => Action::change_own_phone(&mut client),
+            Action::ChangePhone => Action::change_phone(&mut client),
+            Action::AddUser => Action::add_user(&mut client),
+            Action::Login => Action::login(&mut client),
+            Action::Logout => Action::logout(&mut client),
+            Action::UnlockUser => Action::unlock_user(&mut client),
+            Action::EnableTwoFactor => Action::enable_two_factor(&mut client),
+            Action::ChangeOwnPassword => Action::change_own_password(&mut client),
+            Action::ResetPassword => Action::reset_password(&mut client),
+            Action::DeactivateUser => Action::deactivate_user(&mut client),
+            Action::ReactivateUser => Action::reactivate_user(&mut client),
+            Action::DeleteUser => Action::delete_user(&mut client),
+            Action::ChangeRole => Action::change_role(&mut client),
+            Action::ShowAuditLog => Action::show_audit_log(&mut client),
+            Action::ShowUserHistory => Action::show_user_history(&mut client),
+            Action::RevertChange => Action::revert_change(&mut client),
+            Action::ResumeSession => Action::resume_session(&mut client),
+            Action::Exit => Action::exit(&mut client),
+        }
     }
 
-    pub fn show_users(connection: &mut Connection) -> Result<(), Box<dyn Error>> {
-        let res: Result<Vec<UserAccount>, String> = connection.receive()?;
-        match res {
-            Ok(users) => {
+    pub fn show_users(client: &mut Client) -> Result<(), Box<dyn Error>> {
+        match client.request(&Request::ShowUsers)? {
+            Response::Users(users) => {
                 for u in users {
-                    println!("{} - {}", u.username, u.phone_number);
+                    let mut line = u.username;
+                    if let Some(phone_number) = u.phone_number {
+                        line.push_str(&format!(" - {}", phone_number));
+                    }
+                    if let Some(role) = u.role {
+                        line.push_str(&format!(" ({})", role));
+                    }
+                    if let Some(status) = u.status {
+                        line.push_str(&format!(" [{}]", status));
+                    }
+                    println!("{}", line);
                 }
             }
-            Err(e) => {
-                println!("Error while changing phone: {}", e)
-            }
+            Response::Error(e) => println!("Error while showing users: {}", e),
+            res => return Err(unexpected(res)),
         }
 
         Ok(())
     }
 
-    pub fn change_own_phone(connection: &mut Connection) -> Result<(), Box<dyn Error>> {
+    fn print_phone_error(e: ErrorMessage) {
+        match e {
+            ErrorMessage::ErrorConflict(ref current) if !current.is_empty() => println!(
+                "Error while changing phone: {}, the phone number is now {}",
+                e, current
+            ),
+            _ => println!("Error while changing phone: {}", e),
+        }
+    }
+
+    pub fn change_own_phone(client: &mut Client) -> Result<(), Box<dyn Error>> {
         let phone_number = input::<PhoneNumber>()
             .msg("Please enter your new phone number: ")
             .get();
-        connection.send(&phone_number)?;
 
-        let res = connection.receive::<EmptyResult>()?;
-        if let Err(e) = res {
-            println!("Error while changing phone: {}", e);
+        match client.request(&Request::ChangeOwnPhone {
+            phone_number: phone_number.to_string(),
+        })? {
+            Response::Ok => {}
+            Response::Error(e) => Action::print_phone_error(e),
+            res => return Err(unexpected(res)),
         }
 
         Ok(())
     }
 
-    pub fn change_phone(connection: &mut Connection) -> Result<(), Box<dyn Error>> {
+    pub fn change_phone(client: &mut Client) -> Result<(), Box<dyn Error>> {
         let username = input::<Username>().msg("Please enter the username: ").get();
         let phone_number = input::<PhoneNumber>()
             .msg("Please enter the new phone number: ")
             .get();
-        connection.send(&username)?;
-        connection.send(&phone_number)?;
 
-        let res = connection.receive::<EmptyResult>()?;
-        if let Err(e) = res {
-            println!("Error while changing phone: {}", e);
+        match client.request(&Request::ChangePhone {
+            username: username.to_string(),
+            phone_number: phone_number.to_string(),
+        })? {
+            Response::Ok => {}
+            Response::Error(e) => Action::print_phone_error(e),
+            res => return Err(unexpected(res)),
         }
 
         Ok(())
     }
 
-    pub fn add_user(connection: &mut Connection) -> Result<(), Box<dyn Error>> {
+    pub fn add_user(client: &mut Client) -> Result<(), Box<dyn Error>> {
         let username = input::<Username>().msg("Please enter the username: ").get();
         let password = input::<Password>().msg("Please enter the password: ").get();
         let phone_number = input::<PhoneNumber>()
@@ -128,39 +183,415 @@ impl Action {
         let role = input::<UserRole>()
             .msg("Please enter the role (hr/standard_user): ")
             .get();
-        connection.send(&username)?;
-        connection.send(&password)?;
-        connection.send(&phone_number)?;
-        connection.send(&role)?;
 
-        let res = connection.receive::<EmptyResult>()?;
-        if let Err(e) = res {
-            println!("Error while adding user: {}", e);
+        match client.request(&Request::AddUser {
+            username: username.to_string(),
+            password: password.to_string(),
+            phone_number: phone_number.to_string(),
+            role,
+        })? {
+            Response::Ok => {}
+            Response::Error(e) => println!("Error while adding user: {}", e),
+            res => return Err(unexpected(res)),
         }
 
         Ok(())
     }
 
-    pub fn login(connection: &mut Connection) -> Result<(), Box<dyn Error>> {
+    pub fn login(client: &mut Client) -> Result<(), Box<dyn Error>> {
         let username = input::<Username>().msg("Please enter the username: ").get();
         let password = input::<Password>().msg("Please enter the password: ").get();
-        connection.send(&username)?;
-        connection.send(&password)?;
 
-        let res = connection.receive::<EmptyResult>()?;
-        if let Err(e) = res {
-            println!("Error during login: {}", e);
+        let mut res = client.request(&Request::Login {
+            username: username.to_string(),
+            password: password.to_string(),
+        })?;
+        if let Response::TwoFactorRequired = res {
+            let code = input::<AuthCode>()
+                .msg("Please enter the authentication code (or a recovery code): ")
+                .get();
+            res = client.request(&Request::LoginSecondFactor {
+                code: code.to_string(),
+            })?;
+        }
+
+        match res {
+            Response::LoggedIn(token) => *client.session = Some(token),
+            Response::Error(e) => println!("Error during login: {}", e),
+            res => return Err(unexpected(res)),
+        }
+
+        Ok(())
+    }
+
+    pub fn change_own_password(client: &mut Client) -> Result<(), Box<dyn Error>> {
+        let current = input::<String>()
+            .msg("Please enter your current password: ")
+            .get();
+        let new = input::<Password>()
+            .msg("Please enter the new password: ")
+            .get();
+
+        match client.request(&Request::ChangeOwnPassword {
+            current,
+            new: new.to_string(),
+        })? {
+            Response::Ok => println!("Password changed, your other sessions were closed"),
+            Response::Error(e) => println!("Error while changing password: {}", e),
+            res => return Err(unexpected(res)),
+        }
+
+        Ok(())
+    }
+
+    pub fn reset_password(client: &mut Client) -> Result<(), Box<dyn Error>> {
+        let username = input::<Username>().msg("Please enter the username: ").get();
+
+        match client.request(&Request::ResetPassword {
+            username: username.to_string(),
+        })? {
+            Response::TemporaryPassword(temporary) => println!(
+                "Temporary password for {}: {}\nIt must be changed on the next login",
+                username, temporary
+            ),
+            Response::Error(e) => println!("Error while resetting password: {}", e),
+            res => return Err(unexpected(res)),
+        }
+
+        Ok(())
+    }
+
+    pub fn enable_two_factor(client: &mut Client) -> Result<(), Box<dyn Error>> {
+        let setup = match client.request(&Request::EnableTwoFactor)? {
+            Response::TwoFactorSetup(setup) => setup,
+            Response::Error(e) => {
+                println!("Error while setting up two-factor authentication: {}", e);
+                return Ok(());
+            }
+            res => return Err(unexpected(res)),
+        };
+
+        println!("Add this account to your authenticator app:");
+        println!("{}", setup.uri);
+        println!("or enter the secret manually: {}", setup.secret);
+        println!("\nKeep these recovery codes somewhere safe, each can be used once:");
+        for code in &setup.recovery_codes {
+            println!("  {}", code);
+        }
+
+        let code = input::<AuthCode>()
+            .msg("Please enter a code from your app to confirm: ")
+            .get();
+
+        match client.request(&Request::ConfirmTwoFactor {
+            code: code.to_string(),
+        })? {
+            Response::Ok => println!("Two-factor authentication enabled"),
+            Response::Error(e) => {
+                println!("Error while setting up two-factor authentication: {}", e)
+            }
+            res => return Err(unexpected(res)),
+        }
+
+        Ok(())
+    }
+
+    pub fn logout(client: &mut Client) -> Result<(), Box<dyn Error>> {
+        let res = client.request(&Request::Logout)?;
+        *client.session = None;
+        match res {
+            Response::Ok => {}
+            Response::Error(e) => println!("{}", e),
+            res => return Err(unexpected(res)),
+        }
+
+        Ok(())
+    }
+
+    pub fn resume_session(client: &mut Client) -> Result<(), Box<dyn Error>> {
+        let token = client.session.clone().unwrap_or_default();
+
+        match client.request(&Request::ResumeSession { token })? {
+            Response::Ok => {}
+            Response::Error(e) => {
+                *client.session = None;
+                println!("{}", e);
+            }
+            res => return Err(unexpected(res)),
+        }
+
+        Ok(())
+    }
+
+    pub fn exit(client: &mut Client) -> Result<(), Box<dyn Error>> {
+        match client.request(&Request::Exit)? {
+            Response::Ok => Ok(()),
+            res => Err(unexpected(res)),
+        }
+    }
+
+    pub fn unlock_user(client: &mut Client) -> Result<(), Box<dyn Error>> {
+        let username = input::<Username>().msg("Please enter the username: ").get();
+
+        match client.request(&Request::UnlockUser {
+            username: username.to_string(),
+        })? {
+            Response::Ok => {}
+            Response::Error(e) => println!("Error while unlocking user: {}", e),
+            res => return Err(unexpected(res)),
+        }
+
+        Ok(())
+    }
+
+    pub fn deactivate_user(client: &mut Client) -> Result<(), Box<dyn Error>> {
+        let username = input::<Username>().msg("Please enter the username: ").get();
+
+        match client.request(&Request::DeactivateUser {
+            username: username.to_string(),
+        })? {
+            Response::Ok => println!("User {} deactivated, their sessions were closed", username),
+            Response::Error(e) => println!("Error while deactivating user: {}", e),
+            res => return Err(unexpected(res)),
+        }
+
+        Ok(())
+    }
+
+    pub fn reactivate_user(client: &mut Client) -> Result<(), Box<dyn Error>> {
+        let username = input::<Username>().msg("Please enter the username: ").get();
+
+        match client.request(&Request::ReactivateUser {
+            username: username.to_string(),
+        })? {
+            Response::Ok => println!("User {} reactivated", username),
+            Response::Error(e) => println!("Error while reactivating user: {}", e),
+            res => return Err(unexpected(res)),
         }
 
         Ok(())
     }
 
-    pub fn logout(connection: &mut Connection) -> Result<(), Box<dyn Error>> {
-        let res = connection.receive::<EmptyResult>()?;
-        if let Err(e) = res {
-            println!("{}", e);
+    pub fn delete_user(client: &mut Client) -> Result<(), Box<dyn Error>> {
+        let username = input::<Username>().msg("Please enter the username: ").get();
+
+        match client.request(&Request::DeleteUser {
+            username: username.to_string(),
+        })? {
+            Response::Ok => println!("User {} deleted", username),
+            Response::Error(e) => println!("Error while deleting user: {}", e),
+            res => return Err(unexpected(res)),
         }
 
         Ok(())
     }
+
+    pub fn change_role(client: &mut Client) -> Result<(), Box<dyn Error>> {
+        let username = input::<Username>().msg("Please enter the username: ").get();
+        let role = input::<UserRole>()
+            .msg("Please enter the new role (hr/standard_user): ")
+            .get();
+
+        match client.request(&Request::ChangeRole {
+            username: username.to_string(),
+            role,
+        })? {
+            Response::Ok => println!("User {} now has the role {}", username, role),
+            Response::Error(e) => println!("Error while changing role: {}", e),
+            res => return Err(unexpected(res)),
+        }
+
+        Ok(())
+    }
+
+    pub fn show_audit_log(client: &mut Client) -> Result<(), Box<dyn Error>> {
+        println!("Leave a filter empty to match every record");
+        let username = |s: &str| s.parse::<Username>().ok().map(|u| u.to_string());
+        let actor = |s: &str| match s.parse::<Username>() {
+            Ok(u) => Some(u.to_string()),
+            Err(_) => Some(s.to_string()).filter(|s| s == ANONYMOUS),
+        };
+        let day = |s: &str| Date::parse(s, DATE_FORMAT).ok();
+        let query = AuditQuery {
+            actor: optional_input(
+                &format!("Actor ({} for logged-out users): ", ANONYMOUS),
+                actor,
+            ),
+            target: optional_input("Target user: ", username),
+            event: optional_input("Event (e.g. login_failure): ", |s| {
+                Some(s.to_string()).filter(|s| !s.is_empty())
+            }),
+            since: optional_input("From (YYYY-MM-DD): ", day).map(start_of),
+            until: optional_input("To, included (YYYY-MM-DD): ", day)
+                .map(|d| start_of(d.next_day().unwrap_or(d))),
+            page: optional_input("Page (empty for the first): ", |s| s.parse::<u64>().ok())
+                .map_or(0, |p| p.saturating_sub(1)),
+        };
+
+        match client.request(&Request::ShowAuditLog(query))? {
+            Response::AuditLog(page) if page.pages == 0 => println!("No matching record"),
+            Response::AuditLog(page) => {
+                print_audit_table(&page.entries);
+                println!("Page {}/{}", page.page + 1, page.pages);
+            }
+            Response::Error(e) => println!("Error while showing the audit log: {}", e),
+            res => return Err(unexpected(res)),
+        }
+
+        Ok(())
+    }
+
+    pub fn show_user_history(client: &mut Client) -> Result<(), Box<dyn Error>> {
+        let username = input::<Username>().msg("Please enter the username: ").get();
+
+        match client.request(&Request::ShowUserHistory {
+            username: username.to_string(),
+        })? {
+            Response::History(revisions) if revisions.is_empty() => println!("No change recorded"),
+            Response::History(revisions) => print_revisions_table(&revisions),
+            Response::Error(e) => println!("Error while showing the history: {}", e),
+            res => return Err(unexpected(res)),
+        }
+
+        Ok(())
+    }
+
+    pub fn revert_change(client: &mut Client) -> Result<(), Box<dyn Error>> {
+        let username = input::<Username>().msg("Please enter the username: ").get();
+        let version = input::<u64>()
+            .msg("Please enter the revision to revert: ")
+            .get();
+
+        match client.request(&Request::RevertChange {
+            username: username.to_string(),
+            version,
+        })? {
+            Response::Ok => println!("Revision {} of {} reverted", version, username),
+            Response::Error(e) => println!("Error while reverting the change: {}", e),
+            res => return Err(unexpected(res)),
+        }
+
+        Ok(())
+    }
+}
+
+/// The connection along with the session it may be logged in with
+pub struct Client<'a> {
+    connection: &'a mut Connection,
+    session: &'a mut Session,
+}
+
+impl Client<'_> {
+    /// Sends a request and waits for its response. The session is forgotten
+    /// once the server tells it expired.
+    fn request(&mut self, request: &Request) -> Result<Response, Box<dyn Error>> {
+        let response = self.connection.request(request)?;
+        if let Response::Error(ErrorMessage::ErrorSessionExpired) = response {
+            *self.session = None;
+        }
+        Ok(response)
+    }
+}
+
+/// A response the request does not expect, the client and the server do not
+/// agree on the protocol
+fn unexpected(response: Response) -> Box<dyn Error> {
+    format!("Unexpected response from the server: {:?}", response).into()
+}
+
+/// Reads a value that can be left empty, in which case there is none
+fn optional_input<T: 'static>(msg: &str, parse: fn(&str) -> Option<T>) -> Option<T> {
+    let line = input::<String>()
+        .msg(msg)
+        .add_err_test(
+            move |s: &String| s.is_empty() || parse(s).is_some(),
+            "Invalid value, please try again",
+        )
+        .get();
+    parse(&line)
+}
+
+/// Midnight UTC of the day, in seconds since the Unix epoch
+fn start_of(day: Date) -> u64 {
+    day.midnight().assume_utc().unix_timestamp().max(0) as u64
+}
+
+fn format_time(time: u64) -> String {
+    OffsetDateTime::from_unix_timestamp(time as i64)
+        .ok()
+        .and_then(|t| t.format(TIME_FORMAT).ok())
+        .unwrap_or_else(|| time.to_string())
+}
+
+fn print_audit_table(entries: &[AuditEntry]) {
+    let rows: Vec<[String; 6]> = entries
+        .iter()
+        .map(|e| {
+            [
+                e.seq.to_string(),
+                format_time(e.time),
+                e.actor.clone(),
+                e.event.clone(),
+                e.target.clone().unwrap_or_default(),
+                e.details.clone(),
+            ]
+        })
+        .collect();
+    print_table(
+        ["#", "Time (UTC)", "Actor", "Event", "Target", "Details"],
+        &rows,
+    );
+}
+
+fn print_revisions_table(revisions: &[AccountRevision]) {
+    let rows: Vec<[String; 6]> = revisions
+        .iter()
+        .map(|r| {
+            [
+                r.version.to_string(),
+                format_time(r.time),
+                r.actor.clone(),
+                r.field.clone(),
+                r.old.clone(),
+                r.new.clone(),
+            ]
+        })
+        .collect();
+    print_table(
+        [
+            "Revision",
+            "Time (UTC)",
+            "By",
+            "Field",
+            "Old value",
+            "New value",
+        ],
+        &rows,
+    );
+}
+
+/// Prints the rows in columns as wide as their longest cell
+fn print_table<const N: usize>(header: [&str; N], rows: &[[String; N]]) {
+    let header = header.map(String::from);
+    let mut widths = header.clone().map(|h| h.chars().count());
+    for row in rows {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.chars().count());
+        }
+    }
+
+    let line = |row: &[String; N]| {
+        row.iter()
+            .zip(widths)
+            .map(|(cell, width)| format!("{:<width$}", cell, width = width))
+            .collect::<Vec<_>>()
+            .join(" | ")
+            .trim_end()
+            .to_string()
+    };
+    println!("{}", line(&header));
+    println!("{}", widths.map(|w| "-".repeat(w)).join("-+-"));
+    for row in rows {
+        println!("{}", line(row));
+    }
 }
@@ -1,4 +1,5 @@
 use native_tls::TlsStream;
+use protocol::{Request, Response};
 use serde::de::DeserializeOwned;
 use serde::Serialize;
 use std::error::Error;
@@ -6,11 +7,16 @@ use std::net::TcpStream;
 
 pub struct Connection {
     stream: TlsStream<TcpStream>,
+    // Whether `Response::Ready` was received and no request was sent since
+    ready: bool,
 }
 
 impl Connection {
     pub fn new(stream: TlsStream<TcpStream>) -> Connection {
-        Connection { stream }
+        Connection {
+            stream,
+            ready: false,
+        }
     }
 
     pub fn send<T>(&mut self, o: &T) -> Result<(), Box<dyn Error>>
@@ -26,4 +32,26 @@ impl Connection {
     {
         Ok(bincode::deserialize_from(&mut self.stream)?)
     }
+
+    /// Waits until the server is ready for a request, returns its banner
+    pub fn ready(&mut self) -> Result<String, Box<dyn Error>> {
+        match self.receive()? {
+            Response::Ready { banner } => {
+                self.ready = true;
+                Ok(banner)
+            }
+            res => Err(format!("Unexpected response from the server: {:?}", res).into()),
+        }
+    }
+
+    /// Sends a request and waits for the response of the server. If the
+    /// banner was not read yet, it is skipped.
+    pub fn request(&mut self, request: &Request) -> Result<Response, Box<dyn Error>> {
+        if !self.ready {
+            self.ready()?;
+        }
+        self.ready = false;
+        self.send(request)?;
+        self.receive()
+    }
 }
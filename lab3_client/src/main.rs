@@ -5,7 +5,7 @@ mod action;
 /// Tasks todo: - Configure the TLS client properly.
 mod connection;
 
-use crate::action::Action;
+use crate::action::{Action, Session};
 use crate::connection::Connection;
 use native_tls::{Certificate, Protocol, TlsConnector};
 use read_input::prelude::*;
@@ -13,17 +13,28 @@ use std::error::Error;
 use std::fs::File;
 use std::io::Read;
 use std::net::TcpStream;
+use std::thread;
+use std::time::Duration;
 
 // Called once connected to the server, used to execute actions.
-fn client(conn: &mut Connection) -> Result<(), Box<dyn Error>> {
+// Returns once the user chose to exit.
+fn client(conn: &mut Connection, session: &mut Session) -> Result<(), Box<dyn Error>> {
+    // Log back in if the previous connection was lost
+    if session.is_some() {
+        Action::ResumeSession.perform(conn, session)?;
+    }
+
     loop {
-        let banner = conn.receive::<String>()?;
+        let banner = conn.ready()?;
         println!("{}", banner);
 
         Action::display();
         let action = input::<Action>().msg("Please select: ").get();
 
-        action.perform(conn)?;
+        action.perform(conn, session)?;
+        if let Action::Exit = action {
+            return Ok(());
+        }
         println!();
     }
 }
@@ -41,6 +52,22 @@ fn load_server_cert(cert_file: &str) -> Certificate {
 const SERVER_HOST: &str = "localhost";
 const SERVER_PORT: &str = "4444";
 const SERVER_CERT: &str = "keys/sec_lab3_cert.pem";
+const RECONNECT_ATTEMPTS: u32 = 3;
+const RECONNECT_DELAY: Duration = Duration::from_secs(2);
+
+fn connect(connector: &TlsConnector) -> Result<Connection, Box<dyn Error>> {
+    let stream = match TcpStream::connect(format!("{}:{}", SERVER_HOST, SERVER_PORT)) {
+        Ok(stream) => stream,
+        Err(e) => Err(format!("Failed to connect to server: {}", e))?,
+    };
+
+    let stream = match connector.connect(SERVER_HOST, stream) {
+        Ok(stream) => stream,
+        Err(e) => Err(format!("Failed to init TLS: {}", e))?,
+    };
+
+    Ok(Connection::new(stream))
+}
 
 fn main() {
     let server_cert = load_server_cert(SERVER_CERT);
@@ -54,24 +81,25 @@ fn main() {
         .build()
         .expect("Failed to build TlsConnector");
 
-    let stream = match TcpStream::connect(format!("{}:{}", SERVER_HOST, SERVER_PORT)) {
-        Ok(stream) => stream,
-        Err(e) => {
-            eprintln!("Failed to connect to server: {}", e);
-            return;
+    let mut session: Session = None;
+    let mut attempts = 0;
+    loop {
+        let res = connect(&connector).and_then(|mut conn| {
+            attempts = 0;
+            client(&mut conn, &mut session)
+        });
+
+        match res {
+            Ok(_) => return,
+            Err(e) => eprintln!("{}", e),
         }
-    };
 
-    let stream = match connector.connect(SERVER_HOST, stream) {
-        Ok(stream) => stream,
-        Err(e) => {
-            eprintln!("Failed to init TLS: {}", e);
+        // Only worth reconnecting if there is a session to resume
+        attempts += 1;
+        if session.is_none() || attempts > RECONNECT_ATTEMPTS {
             return;
         }
-    };
-
-    let mut conn = Connection::new(stream);
-    if let Err(e) = client(&mut conn) {
-        eprintln!("{}", e);
+        println!("Connection lost, reconnecting...");
+        thread::sleep(RECONNECT_DELAY);
     }
 }
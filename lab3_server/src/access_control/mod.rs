@@ -1,55 +1,454 @@
+mod adapter;
+
+use std::collections::HashSet;
 use std::error::Error;
+use std::str::FromStr;
+use std::sync::RwLock;
 
-use casbin;
-use casbin::CoreApi;
+use casbin::{CoreApi, DefaultModel, MgmtApi};
 use serde::Serialize;
 use strum_macros::{Display, EnumString};
+use tokio::runtime::Handle;
 
-use crate::user::UserAccount;
+use crate::database::UserStore;
+use crate::user::{UserAccount, UserRole};
+use adapter::PolicyFileAdapter;
+use utils::ErrorMessage;
 
-static MODEL: &str = "src/access_control/model.conf";
-static POLICY: &str = "src/access_control/policy.csv";
+// The model and the rules are embedded in the binary, only the changes made to
+// the rules, such as the roles of the users, are persisted next to the database
+static MODEL: &str = include_str!("model.conf");
+static RULES: &str = include_str!("policy.csv");
+static POLICY: &str = "policy.csv";
+// Users are kept apart from the roles in the policy, a username cannot contain
+// a colon so no user can be taken for a role
+static USER_PREFIX: &str = "user:";
 
 #[derive(Clone, Debug, Display, EnumString, Serialize, Hash)]
 pub enum AccessObject {
     #[strum(serialize = "show_users")]
     ShowUsers,
+    // Fields of the directory listing, usernames are always shown
+    #[strum(serialize = "show_users.phone")]
+    ShowPhone,
+    #[strum(serialize = "show_users.role")]
+    ShowRole,
+    #[strum(serialize = "show_users.status")]
+    ShowStatus,
     #[strum(serialize = "change_own_phone")]
     ChangeOwnPhone,
     #[strum(serialize = "change_phone")]
     ChangePhone,
     #[strum(serialize = "add_user")]
     AddUser,
+    #[strum(serialize = "unlock_user")]
+    UnlockUser,
+    #[strum(serialize = "change_own_password")]
+    ChangeOwnPassword,
+    #[strum(serialize = "reset_password")]
+    ResetPassword,
+    #[strum(serialize = "enable_two_factor")]
+    EnableTwoFactor,
+    #[strum(serialize = "deactivate_user")]
+    DeactivateUser,
+    #[strum(serialize = "reactivate_user")]
+    ReactivateUser,
+    #[strum(serialize = "delete_user")]
+    DeleteUser,
+    #[strum(serialize = "change_role")]
+    ChangeRole,
+    #[strum(serialize = "show_audit_log")]
+    ShowAuditLog,
+    #[strum(serialize = "show_user_history")]
+    ShowUserHistory,
+    #[strum(serialize = "revert_change")]
+    RevertChange,
+    // Not an action: users allowed this must use two-factor authentication
+    #[strum(serialize = "require_two_factor")]
+    RequireTwoFactor,
 }
 
 pub struct AccessController {
-    enforcer: casbin::Enforcer,
+    enforcer: RwLock<casbin::Enforcer>,
+    // Used to run the async enforcer API from the client threads
+    runtime: Handle,
 }
 
 impl AccessController {
-    pub async fn new() -> Result<Self, Box<dyn Error>> {
-        let mut e = casbin::Enforcer::new(MODEL, POLICY).await?;
+    pub async fn new(store: &dyn UserStore) -> Result<Self, Box<dyn Error>> {
+        Self::open(POLICY, store).await
+    }
+
+    /// Loads the roles of the users from the policy file at `path`
+    pub async fn open(path: &str, store: &dyn UserStore) -> Result<Self, Box<dyn Error>> {
+        let adapter = PolicyFileAdapter::new(path, RULES);
+        let outdated = adapter.is_outdated()?;
+        if outdated {
+            info!("Upgrading {} to the current format", path);
+        }
+
+        let model = DefaultModel::from_str(MODEL).await?;
+        let mut e = casbin::Enforcer::new(model, adapter).await?;
         e.enable_log(true); // On pourrait faire en sorte de mieux l'intégrer avec simplelog
-        Ok(Self { enforcer: e })
+
+        // The role of each user is taken from the database rather than the
+        // policy file, which may be out of date if the store was replaced
+        let expected: HashSet<Vec<String>> = store.list()?.iter().map(grouping).collect();
+        let mut changed = outdated;
+        for rule in e.get_named_grouping_policy("g") {
+            if !is_user_grouping(&rule) || expected.contains(&rule) {
+                continue;
+            }
+            warn!("Removing stale grouping {:?} from {}", rule, path);
+            changed |= e.remove_grouping_policy(rule).await?;
+        }
+        for rule in expected {
+            // Added one by one, adding several fails if any of them exists
+            changed |= e.add_grouping_policy(rule).await?;
+        }
+        if changed {
+            e.save_policy().await?;
+        }
+
+        Ok(Self {
+            enforcer: RwLock::new(e),
+            runtime: Handle::current(),
+        })
     }
 
     pub fn enforce(&self, req: Request) -> Result<bool, Box<dyn Error>> {
-        let res = self
-            .enforcer
-            .enforce((&req.username, &req.object.to_string(), "access"));
+        let res = self.enforcer.read().unwrap().enforce((
+            &req.username,
+            &req.object.to_string(),
+            "access",
+            &req.target,
+        ));
         Ok(res?)
     }
+
+    /// Gives a user the permissions of its role
+    pub fn add_role(&self, user: &UserAccount) -> Result<(), Box<dyn Error>> {
+        if self.add_grouping("g", grouping(user))? {
+            debug!("User {} added to role {}", user.username(), user.role());
+        }
+        Ok(())
+    }
+
+    /// Changes the role of a user in the store and in the policy. Both are
+    /// done under the policy lock, so role changes cannot interleave and the
+    /// last active HR account cannot be demoted by two changes at once.
+    pub fn change_role(
+        &self,
+        store: &dyn UserStore,
+        user: &UserAccount,
+        role: UserRole,
+        actor: &str,
+    ) -> Result<Result<(), ErrorMessage>, Box<dyn Error>> {
+        let mut e = self.enforcer.write().unwrap();
+
+        if role != UserRole::HR && is_last_hr(store, user)? {
+            return Ok(Err(ErrorMessage::ErrorLastHR));
+        }
+
+        let mut changed = user.clone();
+        changed.set_role(role, actor);
+        if !store.update(&changed)? {
+            return Ok(Err(ErrorMessage::ErrorConflict(String::new())));
+        }
+
+        let res = self.runtime.block_on(async {
+            e.remove_grouping_policy(grouping(user)).await?;
+            e.add_grouping_policy(grouping(&changed)).await?;
+            e.save_policy().await
+        });
+        if let Err(err) = res {
+            // Put the account and the policy back as they were, so they agree
+            warn!("Could not save the role of {}, reverting", user.username());
+            // The account as it was, with the version of the failed write
+            store.update(&user.next_version())?;
+            self.runtime.block_on(e.load_policy())?;
+            return Err(err.into());
+        }
+
+        debug!("User {} moved to role {}", user.username(), role);
+        Ok(Ok(()))
+    }
+
+    /// Deactivates or reactivates an account, under the policy lock like
+    /// `change_role`, so the last active HR account cannot be deactivated.
+    pub fn set_active(
+        &self,
+        store: &dyn UserStore,
+        user: &UserAccount,
+        active: bool,
+    ) -> Result<Result<(), ErrorMessage>, Box<dyn Error>> {
+        let _e = self.enforcer.write().unwrap();
+
+        if !active && is_last_hr(store, user)? {
+            return Ok(Err(ErrorMessage::ErrorLastHR));
+        }
+
+        let mut changed = user.clone();
+        changed.set_active(active);
+        if !store.update(&changed)? {
+            return Ok(Err(ErrorMessage::ErrorConflict(String::new())));
+        }
+        Ok(Ok(()))
+    }
+
+    /// Deletes an account and its role, under the policy lock like
+    /// `change_role`, so the last active HR account cannot be deleted.
+    pub fn delete_user(
+        &self,
+        store: &dyn UserStore,
+        username: &str,
+    ) -> Result<Result<UserAccount, ErrorMessage>, Box<dyn Error>> {
+        let mut e = self.enforcer.write().unwrap();
+
+        // Read again under the lock, its role cannot change anymore
+        let user = match store.get(username)? {
+            Some(user) => user,
+            None => return Ok(Err(ErrorMessage::ErrorUserNotFound)),
+        };
+        if is_last_hr(store, &user)? {
+            return Ok(Err(ErrorMessage::ErrorLastHR));
+        }
+        if !store.delete(username)? {
+            return Ok(Err(ErrorMessage::ErrorUserNotFound));
+        }
+
+        // Nothing may be left that a new account with the same name would
+        // inherit. Should saving fail, the stale grouping is removed at the
+        // next start, when the policy is rebuilt from the store.
+        let removed = self
+            .runtime
+            .block_on(e.remove_grouping_policy(grouping(&user)))?;
+        self.save_if(removed, &mut e)?;
+        debug!("User {} removed from role {}", username, user.role());
+        Ok(Ok(user))
+    }
+
+    // The following functions change the policy and persist it. They return
+    // false if there was nothing to change.
+
+    #[allow(dead_code)]
+    pub fn add_policy(&self, rule: Vec<String>) -> Result<bool, Box<dyn Error>> {
+        let mut e = self.enforcer.write().unwrap();
+        let changed = self.runtime.block_on(e.add_policy(rule))?;
+        self.save_if(changed, &mut e)
+    }
+
+    #[allow(dead_code)]
+    pub fn remove_policy(&self, rule: Vec<String>) -> Result<bool, Box<dyn Error>> {
+        let mut e = self.enforcer.write().unwrap();
+        let changed = self.runtime.block_on(e.remove_policy(rule))?;
+        self.save_if(changed, &mut e)
+    }
+
+    /// `ptype` is "g" for the roles of the subjects, "g2" for the groups of
+    /// the objects
+    pub fn add_grouping(&self, ptype: &str, rule: Vec<String>) -> Result<bool, Box<dyn Error>> {
+        let mut e = self.enforcer.write().unwrap();
+        let changed = self
+            .runtime
+            .block_on(e.add_named_grouping_policy(ptype, rule))?;
+        self.save_if(changed, &mut e)
+    }
+
+    #[allow(dead_code)]
+    pub fn remove_grouping(&self, ptype: &str, rule: Vec<String>) -> Result<bool, Box<dyn Error>> {
+        let mut e = self.enforcer.write().unwrap();
+        let changed = self
+            .runtime
+            .block_on(e.remove_named_grouping_policy(ptype, rule))?;
+        self.save_if(changed, &mut e)
+    }
+
+    fn save_if(&self, changed: bool, e: &mut casbin::Enforcer) -> Result<bool, Box<dyn Error>> {
+        if changed {
+            self.runtime.block_on(e.save_policy())?;
+            debug!("Policy saved");
+        }
+        Ok(changed)
+    }
+}
+
+/// Name of a user in the policy
+fn subject(username: &str) -> String {
+    format!("{}{}", USER_PREFIX, username)
+}
+
+fn grouping(user: &UserAccount) -> Vec<String> {
+    vec![subject(user.username()), user.role().to_string()]
+}
+
+/// Whether the user is the only active HR account. To be called with the
+/// policy locked for writing, role changes are then excluded.
+fn is_last_hr(store: &dyn UserStore, user: &UserAccount) -> Result<bool, Box<dyn Error>> {
+    if *user.role() != UserRole::HR || !user.is_active() {
+        return Ok(false);
+    }
+    let other_hr = store
+        .list()?
+        .iter()
+        .any(|u| *u.role() == UserRole::HR && u.is_active() && u.username() != user.username());
+    Ok(!other_hr)
+}
+
+/// Whether the rule gives a role to a user, rather than to another role
+fn is_user_grouping(rule: &[String]) -> bool {
+    rule.first()
+        .is_some_and(|sub| UserRole::from_str(sub).is_err())
 }
 
 #[derive(Clone, Debug, Serialize, Hash)]
 pub struct Request {
     pub username: String,
     pub object: AccessObject,
+    pub target: Target,
+}
+
+/// The account an action is performed on, empty if there is none
+#[derive(Clone, Debug, Default, Serialize, Hash)]
+pub struct Target {
+    pub name: String,
+    pub role: String,
 }
 
 impl Request {
     pub fn new(user: &UserAccount, object: AccessObject) -> Self {
-        let username = user.username().to_string();
-        Self { username, object }
+        let username = subject(user.username());
+        Self {
+            username,
+            object,
+            target: Target::default(),
+        }
+    }
+
+    /// Request made by a user who is not logged in, evaluated as the anonymous
+    /// role
+    pub fn anonymous(object: AccessObject) -> Self {
+        Self {
+            username: UserRole::Anon.to_string(),
+            object,
+            target: Target::default(),
+        }
+    }
+
+    /// Request on the account `target`, which may not exist yet
+    pub fn with_target(user: &UserAccount, object: AccessObject, target: (&str, UserRole)) -> Self {
+        let target = Target {
+            name: subject(target.0),
+            role: target.1.to_string(),
+        };
+        Self {
+            target,
+            ..Self::new(user, object)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::MemoryStore;
+    use crate::testing::{account, TestPolicy};
+
+    fn get(store: &dyn UserStore, username: &str) -> UserAccount {
+        store.get(username).unwrap().unwrap()
+    }
+
+    #[test]
+    fn last_active_hr_is_kept() {
+        let store = MemoryStore::new(vec![
+            account("harry", UserRole::HR),
+            account("hanna", UserRole::HR),
+        ]);
+        let policy = TestPolicy::new(&store);
+        let ac = &policy.ac;
+
+        let harry = get(&store, "harry");
+        assert!(ac.set_active(&store, &harry, false).unwrap().is_ok());
+
+        // An inactive HR account does not count
+        let hanna = get(&store, "hanna");
+        assert!(matches!(
+            ac.set_active(&store, &hanna, false).unwrap(),
+            Err(ErrorMessage::ErrorLastHR)
+        ));
+        assert!(matches!(
+            ac.delete_user(&store, "hanna").unwrap(),
+            Err(ErrorMessage::ErrorLastHR)
+        ));
+        assert!(matches!(
+            ac.change_role(&store, &hanna, UserRole::StandardUser, "harry")
+                .unwrap(),
+            Err(ErrorMessage::ErrorLastHR)
+        ));
+        assert!(get(&store, "hanna").is_active());
+
+        let harry = get(&store, "harry");
+        assert!(ac.set_active(&store, &harry, true).unwrap().is_ok());
+        assert!(ac.delete_user(&store, "hanna").unwrap().is_ok());
+        assert!(store.get("hanna").unwrap().is_none());
+        assert!(!std::fs::read_to_string(&policy.path)
+            .unwrap()
+            .contains(&subject("hanna")));
+    }
+
+    #[test]
+    fn hr_cannot_reset_own_password() {
+        let anne = account("anne", UserRole::StandardUser);
+        let harry = account("harry", UserRole::HR);
+        let hanna = account("hanna", UserRole::HR);
+        let store = MemoryStore::new(vec![anne.clone(), harry.clone(), hanna.clone()]);
+        let policy = TestPolicy::new(&store);
+
+        let reset = |target: &UserAccount| {
+            let target = (target.username(), *target.role());
+            let req = Request::with_target(&harry, AccessObject::ResetPassword, target);
+            policy.ac.enforce(req).unwrap()
+        };
+        assert!(reset(&anne));
+        assert!(reset(&hanna));
+        assert!(!reset(&harry));
+    }
+
+    #[test]
+    fn runtime_rules_survive_restart() {
+        let anne = account("anne", UserRole::StandardUser);
+        let harry = account("harry", UserRole::HR);
+        let store = MemoryStore::new(vec![anne.clone(), harry.clone()]);
+        let mut policy = TestPolicy::new(&store);
+
+        let strs = |rule: &[&str]| rule.iter().map(|s| s.to_string()).collect();
+        let allowed = |ac: &AccessController, user: &UserAccount, object| {
+            ac.enforce(Request::new(user, object)).unwrap()
+        };
+        assert!(!allowed(&policy.ac, &anne, AccessObject::ShowAuditLog));
+        assert!(allowed(&policy.ac, &harry, AccessObject::ShowStatus));
+
+        let ac = &policy.ac;
+        assert!(ac
+            .add_policy(strs(&["standard_user", "show_audit_log", "access", "*"]))
+            .unwrap());
+        assert!(ac
+            .remove_grouping("g2", strs(&["show_users.status", "admin"]))
+            .unwrap());
+        assert!(ac
+            .add_grouping("g2", strs(&["show_users.role", "directory"]))
+            .unwrap());
+        assert!(ac
+            .remove_policy(strs(&["hr", "admin", "access", "*"]))
+            .unwrap());
+
+        policy.reopen(&store);
+        let ac = &policy.ac;
+        assert!(allowed(ac, &anne, AccessObject::ShowAuditLog));
+        assert!(!allowed(ac, &harry, AccessObject::ShowStatus));
+        assert!(allowed(ac, &anne, AccessObject::ShowRole));
+        assert!(!allowed(ac, &harry, AccessObject::AddUser));
+        // The roles of the users are kept along with the changes
+        assert!(allowed(ac, &harry, AccessObject::ShowPhone));
     }
 }
@@ -4,240 +4,1000 @@
 ///             - Input/output validation
 ///             - Log stuff whenever required
 ///             - Potential improvements
-use crate::access_control::{AccessController, AccessObject, Request};
+use crate::access_control::{AccessController, AccessObject, Request as AccessRequest};
+use crate::audit::{AuditEvent, AuditLog};
 use crate::connection::Connection;
-use crate::database::Database;
-use crate::user::{UserAccount, UserRole};
-use serde::{Deserialize, Serialize};
+use crate::database::UserStore;
+use crate::password;
+use crate::session::SessionManager;
+use crate::throttle::Throttle;
+use crate::two_factor;
+use crate::user::{AccountField, UserAccount, UserRole};
+use protocol::{
+    AccountRevision, AuditQuery, PublicUser, Request, Response, TwoFactorSetup, ANONYMOUS,
+};
 use std::error::Error;
+use std::net::IpAddr;
+use std::str::FromStr;
 use std::sync::Arc;
-use strum_macros::{EnumIter, EnumString};
 
 use utils::ErrorMessage;
 use validation::Validator;
 
-#[derive(Serialize, Deserialize, Debug, EnumString, EnumIter)]
-pub enum Action {
-    #[strum(serialize = "Show users", serialize = "1")]
-    ShowUsers,
-    #[strum(serialize = "Change my phone number", serialize = "2")]
-    ChangeOwnPhone,
-    #[strum(serialize = "Show someone's phone number", serialize = "3")]
-    ChangePhone,
-    #[strum(serialize = "Add user", serialize = "4")]
-    AddUser,
-    #[strum(serialize = "Login", serialize = "5")]
-    Login,
-    #[strum(serialize = "Logout", serialize = "6")]
-    Logout,
-    #[strum(serialize = "Exit", serialize = "7")]
-    Exit,
+/// Fails with the validation error of the first invalid username
+fn validate_usernames<'a>(
+    mut usernames: impl Iterator<Item = &'a String>,
+) -> Result<(), ErrorMessage> {
+    usernames.try_for_each(|name| Validator::validate_username(name))
 }
 
 /// The individual actions are implemented with three main steps:
-///     1. Read client inputs if required
+///     1. Validate the inputs of the request
 ///     2. Execute various server code
-///     3. Send a result
-impl Action {
-    pub fn perform(&self, u: &mut ConnectedUser) -> Result<(), Box<dyn Error>> {
-        let res = match self {
-            Action::ShowUsers => Action::show_users(u),
-            Action::ChangeOwnPhone => Action::change_own_phone(u),
-            Action::ChangePhone => Action::change_target_phone(u),
-            Action::AddUser => Action::add_user(u),
-            Action::Login => Action::login(u),
-            Action::Logout => Action::logout(u),
-            Action::Exit => Err("Client disconnected")?,
-        };
+///     3. Return the response
+pub fn perform(u: &mut ConnectedUser, request: Request) -> Result<Response, Box<dyn Error>> {
+    // Only valid right after the request that started them
+    let pending_login = u.pending_login.take();
+    let pending_two_factor = u.pending_two_factor.take();
+
+    // Leaving is always allowed, an expired session is closed all the same
+    if !matches!(request, Request::Exit) {
+        if let Err(e) = u.check_session() {
+            return Ok(e.into());
+        }
+    }
+    if let Err(e) = check_allowed(u, &request)? {
+        return Ok(e.into());
+    }
+
+    match request {
+        Request::ShowUsers => show_users(u),
+        Request::ChangeOwnPhone { phone_number } => change_own_phone(u, phone_number),
+        Request::ChangePhone {
+            username,
+            phone_number,
+        } => change_target_phone(u, username, phone_number),
+        Request::AddUser {
+            username,
+            password,
+            phone_number,
+            role,
+        } => add_user(u, username, password, phone_number, role),
+        Request::Login { username, password } => login(u, username, password),
+        Request::LoginSecondFactor { code } => login_second_factor(u, pending_login, code),
+        Request::Logout => logout(u),
+        Request::UnlockUser { username } => unlock_user(u, username),
+        Request::EnableTwoFactor => enable_two_factor(u),
+        Request::ConfirmTwoFactor { code } => confirm_two_factor(u, pending_two_factor, code),
+        Request::ChangeOwnPassword { current, new } => change_own_password(u, current, new),
+        Request::ResetPassword { username } => reset_password(u, username),
+        Request::DeactivateUser { username } => set_user_active(u, username, false),
+        Request::ReactivateUser { username } => set_user_active(u, username, true),
+        Request::DeleteUser { username } => delete_user(u, username),
+        Request::ChangeRole { username, role } => change_role(u, username, role),
+        Request::ShowAuditLog(query) => show_audit_log(u, query),
+        Request::ShowUserHistory { username } => show_user_history(u, username),
+        Request::RevertChange { username, version } => revert_change(u, username, version),
+        Request::ResumeSession { token } => resume_session(u, token),
+        Request::Exit => {
+            u.logout();
+            Ok(Response::Ok)
+        }
+    }
+}
+
+/// Checked before performing the request. A user whose password was reset
+/// can only change it, and a user whose role requires two-factor
+/// authentication can only set it up until it is enabled.
+fn check_allowed(
+    u: &mut ConnectedUser,
+    request: &Request,
+) -> Result<Result<(), ErrorMessage>, Box<dyn Error>> {
+    Ok(match request {
+        Request::ChangeOwnPassword { .. } | Request::Logout | Request::Exit => Ok(()),
+        _ if u.must_change_password()? => {
+            warn!("{} must change its password first", u.name());
+            Err(ErrorMessage::ErrorPasswordChangeRequired)
+        }
+        Request::EnableTwoFactor | Request::ConfirmTwoFactor { .. } => Ok(()),
+        _ if u.must_enable_two_factor()? => {
+            warn!("{} must set up two-factor authentication first", u.name());
+            Err(ErrorMessage::ErrorTwoFactorRequired)
+        }
+        _ => Ok(()),
+    })
+}
+
+fn show_users(u: &mut ConnectedUser) -> Result<Response, Box<dyn Error>> {
+    trace!("Show users");
+    let caller = if u.is_anonymous() {
+        None
+    } else {
+        Some(u.user_account()?)
+    };
+    let users = list_users(u.store.as_ref(), &u.ac, &u.throttle, caller.as_ref())?;
+    Ok(Response::Users(users))
+}
+
+/// The directory as seen by `caller`, `None` if not logged in. Only the
+/// fields the caller may read are set. Unlike actions, anonymous users are
+/// given the permissions of their role.
+fn list_users(
+    store: &dyn UserStore,
+    ac: &AccessController,
+    throttle: &Throttle,
+    caller: Option<&UserAccount>,
+) -> Result<Vec<PublicUser>, Box<dyn Error>> {
+    let can_read = |field| {
+        ac.enforce(match caller {
+            Some(user) => AccessRequest::new(user, field),
+            None => AccessRequest::anonymous(field),
+        })
+    };
+    let show_phone = can_read(AccessObject::ShowPhone)?;
+    let show_role = can_read(AccessObject::ShowRole)?;
+    let show_status = can_read(AccessObject::ShowStatus)?;
 
-        res
+    Ok(store
+        .list()?
+        .into_iter()
+        .map(|user| PublicUser {
+            username: user.username().to_string(),
+            phone_number: show_phone.then(|| user.phone_number.clone()),
+            role: show_role.then(|| user.role().to_string()),
+            status: show_status.then(|| user.status(throttle.is_locked(user.username()))),
+        })
+        .collect())
+}
+
+fn change_own_phone(u: &mut ConnectedUser, phone: String) -> Result<Response, Box<dyn Error>> {
+    trace!("Change own phone");
+
+    // Anonymous users have no account to change, they are denied, and audited,
+    // by `is_authorized`
+    if u.is_anonymous() && !u.is_authorized(AccessObject::ChangeOwnPhone, None)? {
+        warn!("Anonymous tried to change phone number");
+        return Ok(ErrorMessage::ErrorNotAuthorized.into());
     }
 
-    pub fn show_users(u: &mut ConnectedUser) -> Result<(), Box<dyn Error>> {
-        trace!("Show users");
-        let users = Database::values()?;
-        let res: Result<Vec<UserAccount>, &str> = Ok(users);
-        u.conn().send(&res)
+    let mut user_account = u.user_account()?;
+    change_phone(
+        u,
+        &mut user_account,
+        phone,
+        Some(AccessObject::ChangeOwnPhone),
+    )
+}
+
+fn change_target_phone(
+    u: &mut ConnectedUser,
+    target: String,
+    phone: String,
+) -> Result<Response, Box<dyn Error>> {
+    trace!("Change target phone");
+
+    if let Err(e) = Validator::validate_username(target.as_str()) {
+        return Ok(e.into());
     }
 
-    pub fn change_own_phone(u: &mut ConnectedUser) -> Result<(), Box<dyn Error>> {
-        trace!("Change own phone");
-        let phone = u.conn().receive::<String>()?;
+    match u.store.get(&target)? {
+        Some(mut t) => change_phone(u, &mut t, phone, None),
+        None => {
+            warn!("User {} not found", target);
+            Ok(ErrorMessage::ErrorUserNotFound.into())
+        }
+    }
+}
 
-        // Check permissions
-        if u.is_anonymous() {
-            warn!("Anonymous tried to change phone number");
-            u.conn()
-                .send::<Result<(), ErrorMessage>>(&Err(ErrorMessage::ErrorNotAuthorized))
+fn change_phone(
+    u: &mut ConnectedUser,
+    target: &mut UserAccount,
+    phone: String,
+    object: Option<AccessObject>,
+) -> Result<Response, Box<dyn Error>> {
+    trace!("Change phone");
+
+    if let Err(e) = Validator::validate_phone_number(phone.as_str()) {
+        return Ok(e.into());
+    }
+
+    let object = object.unwrap_or(AccessObject::ChangePhone);
+
+    // Check permissions
+    let res = if !u.is_authorized(object, Some(target))? {
+        warn!(
+            "{} tried to change phone number for {}",
+            u.name(),
+            target.username()
+        );
+        Err(ErrorMessage::ErrorNotAuthorized)
+    } else {
+        let old = target.phone_number.clone();
+        target.set_phone_number(phone, &u.name());
+        if u.store.update(target)? {
+            info!("Changed phone number for {}", target.username());
+            u.audit(
+                AuditEvent::PhoneChanged,
+                Some(target.username()),
+                format!("{} -> {}", old, target.phone_number),
+            )?;
+            Ok(())
         } else {
-            let mut user_account = u.user_account()?;
-            Self::change_phone(
-                u,
-                &mut user_account,
-                phone,
-                Some(AccessObject::ChangeOwnPhone),
-            )
+            // The client is told the number set by the other change
+            warn!("Conflicting phone number change for {}", target.username());
+            match u.store.get(target.username())? {
+                Some(current) => Err(ErrorMessage::ErrorConflict(current.phone_number)),
+                None => Err(ErrorMessage::ErrorUserNotFound),
+            }
         }
+    };
+
+    Ok(res.into())
+}
+
+fn add_user(
+    u: &mut ConnectedUser,
+    username: String,
+    password: String,
+    phone: String,
+    role: UserRole,
+) -> Result<Response, Box<dyn Error>> {
+    trace!("Add user");
+
+    if let Err(e) = Validator::validate_username(&username) {
+        return Ok(e.into());
+    } else if let Err(e) = Validator::validate_password(&password) {
+        return Ok(e.into());
+    } else if let Err(e) = Validator::validate_phone_number(&phone) {
+        return Ok(e.into());
+    }
+
+    // Checked before hashing the password, so unauthorized users cannot make
+    // the server hash at will
+    if !u.is_authorized_on(AccessObject::AddUser, Some((&username, role)))? {
+        warn!("{} tried to add user {}", u.name(), username);
+        return Ok(ErrorMessage::ErrorNotAuthorized.into());
+    } else if role == UserRole::Anon {
+        // Only given to users who are not logged in
+        warn!(
+            "{} tried to add user {} with the role {}",
+            u.name(),
+            username,
+            role
+        );
+        return Ok(ErrorMessage::ErrorNotAuthorized.into());
     }
 
-    pub fn change_target_phone(u: &mut ConnectedUser) -> Result<(), Box<dyn Error>> {
-        trace!("Change target phone");
-        let target = u.conn().receive::<String>()?;
-        let phone = u.conn().receive::<String>()?;
+    let user = UserAccount::new(username, password, phone, role)?;
+    let res = if !u.store.insert(&user)? {
+        warn!("User {} already exists", user.username());
+        Err(ErrorMessage::ErrorUserAlreadyExists)
+    } else {
+        info!("Added user {}", user.username());
+        u.ac.add_role(&user)?;
+        u.audit(
+            AuditEvent::UserCreated,
+            Some(user.username()),
+            format!("role {}", user.role()),
+        )?;
+        Ok(())
+    };
 
-        if let Err(e) = Validator::validate_username(target.as_str()) {
-            return u.conn().send::<Result<(), ErrorMessage>>(&Err(e));
+    Ok(res.into())
+}
+
+fn login(
+    u: &mut ConnectedUser,
+    username: String,
+    password: String,
+) -> Result<Response, Box<dyn Error>> {
+    trace!("Login");
+
+    if let Err(e) = Validator::validate_username(&username) {
+        return Ok(e.into());
+    } else if let Err(e) = Validator::validate_password(&password) {
+        return Ok(e.into());
+    }
+
+    let addr = u.conn().peer_ip()?;
+    if !u.is_anonymous() {
+        info!("User already logged in");
+        return Ok(ErrorMessage::ErrorIsLoggedIn.into());
+    }
+    let attempt = match u.throttle.begin(&username, addr) {
+        Ok(attempt) => attempt,
+        Err(e) => {
+            u.audit_login_failure(&username, addr, "too many attempts")?;
+            return Ok(e.into());
         }
+    };
 
-        match Database::get(&target)? {
-            Some(mut t) => Self::change_phone(u, &mut t, phone, None),
-            None => {
-                warn!("User {} not found", target);
-                u.conn()
-                    .send::<Result<(), ErrorMessage>>(&Err(ErrorMessage::ErrorUserNotFound))
+    let (user, valid) = check_password(u.store.as_ref(), &username, &password)?;
+    let res = match user {
+        Some(user) if valid && !user.is_active() => {
+            // Only told once the password is known to be right
+            warn!("Deactivated user {} tried to log in", username);
+            u.audit_login_failure(&username, addr, "account deactivated")?;
+            Err(ErrorMessage::ErrorAccountDeactivated)
+        }
+        Some(mut user) if valid => {
+            if password::needs_rehash(user.password_hash()) {
+                let old = user.clone();
+                user.set_password(&password)?;
+                // Not an issue if someone else changed the account,
+                // the hash will be upgraded on the next login
+                if u.store.compare_and_swap(&old, &user)? {
+                    info!("Upgraded password hash of user {}", username);
+                }
+            }
+
+            if user.has_two_factor() {
+                // The code is another attempt, throttled on its own
+                debug!("Waiting for the second factor of {}", username);
+                u.pending_login = Some(username);
+                return Ok(Response::TwoFactorRequired);
             }
+
+            attempt.succeeded();
+            if user.must_change_password() {
+                info!("User {} logged in with a temporary password", username);
+            } else {
+                info!("User {} logged in", username);
+            }
+            let token = u.start_session(&username);
+            u.audit(
+                AuditEvent::LoginSuccess,
+                Some(&username),
+                format!("from {}", addr),
+            )?;
+            Ok(token)
         }
-    }
+        Some(_) => {
+            warn!("Wrong password for user {}", username);
+            attempt.failed();
+            u.audit_login_failure(&username, addr, "wrong password")?;
+            Err(ErrorMessage::ErrorLogin)
+        }
+        None => {
+            warn!("User {} not found", username);
+            attempt.failed();
+            u.audit_login_failure(&username, addr, "unknown user")?;
+            Err(ErrorMessage::ErrorLogin)
+        }
+    };
 
-    pub fn change_phone(
-        u: &mut ConnectedUser,
-        target: &mut UserAccount,
-        phone: String,
-        object: Option<AccessObject>,
-    ) -> Result<(), Box<dyn Error>> {
-        trace!("Change phone");
-        // Receive data
+    Ok(res.map_or_else(Response::Error, Response::LoggedIn))
+}
+
+/// Looks up the user and verifies its password. The password is always
+/// verified, against a dummy hash if the user does not exist, so both cases
+/// take the same time.
+fn check_password(
+    store: &dyn UserStore,
+    username: &str,
+    password: &str,
+) -> Result<(Option<UserAccount>, bool), Box<dyn Error>> {
+    Ok(match store.get(username)? {
+        Some(user) => {
+            let valid = password::verify(user.password_hash(), password);
+            (Some(user), valid)
+        }
+        None => (None, password::verify_dummy(password)),
+    })
+}
+
+/// Completes the login of `pending`, the user whose password was verified
+/// by the previous request
+fn login_second_factor(
+    u: &mut ConnectedUser,
+    pending: Option<String>,
+    code: String,
+) -> Result<Response, Box<dyn Error>> {
+    trace!("Login second factor");
+    let addr = u.conn().peer_ip()?;
+
+    // The account is read again, it may have changed since the password
+    // was verified
+    let mut user = match pending.map(|p| u.store.get(&p)).transpose()?.flatten() {
+        Some(user) if user.is_active() => user,
+        _ => {
+            warn!("Authentication code sent without a pending login");
+            return Ok(ErrorMessage::ErrorLogin.into());
+        }
+    };
 
-        if let Err(e) = Validator::validate_phone_number(phone.as_str()) {
-            return u.conn().send::<Result<(), ErrorMessage>>(&Err(e));
+    let attempt = match u.throttle.begin(user.username(), addr) {
+        Ok(attempt) => attempt,
+        Err(e) => {
+            u.audit_login_failure(user.username(), addr, "too many attempts")?;
+            return Ok(e.into());
         }
+    };
 
-        let object = object.unwrap_or(AccessObject::ChangePhone);
+    let res = if let Err(e) = Validator::validate_auth_code(&code) {
+        attempt.failed();
+        u.audit_login_failure(user.username(), addr, "invalid code")?;
+        Err(e)
+    } else if !user.check_second_factor(&code)? {
+        warn!("Wrong authentication code for user {}", user.username());
+        attempt.failed();
+        u.audit_login_failure(user.username(), addr, "wrong code")?;
+        Err(ErrorMessage::ErrorInvalidCode)
+    } else if !u.store.update(&user)? {
+        // Saved in case a recovery code was used up, so the login cannot
+        // go on without it
+        Err(conflict(&user))
+    } else {
+        attempt.succeeded();
+        info!(
+            "User {} logged in with two-factor authentication",
+            user.username()
+        );
+        let token = u.start_session(user.username());
+        u.audit(
+            AuditEvent::LoginSuccess,
+            Some(user.username()),
+            format!("from {} with two-factor authentication", addr),
+        )?;
+        Ok(token)
+    };
 
-        // Check permissions
-        let current_user = u.user_account()?;
-        let perm = u.ac.enforce(Request::new(&current_user, object))?;
-        let res = if perm {
-            info!("Changing phone number for {}", target.username());
-            target.set_phone_number(phone);
-            Database::insert(&target)?;
-            Ok(())
-        } else {
-            warn!(
-                "{} tried to change phone number for {}",
-                current_user.username(),
-                target.username()
-            );
+    Ok(res.map_or_else(Response::Error, Response::LoggedIn))
+}
+
+fn change_own_password(
+    u: &mut ConnectedUser,
+    current: String,
+    new: String,
+) -> Result<Response, Box<dyn Error>> {
+    trace!("Change own password");
+
+    let res = if u.is_anonymous() {
+        Err(ErrorMessage::ErrorNotLoggedIn)
+    } else if let Err(e) = Validator::validate_password(&new) {
+        Err(e)
+    } else {
+        let mut user = u.user_account()?;
+        let addr = u.conn().peer_ip()?;
+        if !u.is_authorized(AccessObject::ChangeOwnPassword, Some(&user))? {
+            warn!("{} tried to change its password", u.name());
             Err(ErrorMessage::ErrorNotAuthorized)
-        };
+        } else {
+            let attempt = match u.throttle.begin(user.username(), addr) {
+                Ok(attempt) => attempt,
+                Err(e) => return Ok(e.into()),
+            };
+            if !password::verify(user.password_hash(), &current) {
+                // Guessing the current password is as good as guessing it
+                // on the login
+                warn!("Wrong current password for {}", user.username());
+                attempt.failed();
+                Err(ErrorMessage::ErrorWrongPassword)
+            } else if user.is_recent_password(&new) {
+                Err(ErrorMessage::ErrorPasswordReused)
+            } else if !user
+                .change_password(&new)
+                .and_then(|_| u.store.update(&user))?
+            {
+                Err(conflict(&user))
+            } else {
+                attempt.succeeded();
 
-        u.conn().send(&res)
-    }
-
-    pub fn add_user(u: &mut ConnectedUser) -> Result<(), Box<dyn Error>> {
-        trace!("Add user");
-        // Receive data
-        let username = u.conn().receive::<String>()?;
-        let password = u.conn().receive::<String>()?;
-        let phone = u.conn().receive::<String>()?;
-        let role = u.conn().receive::<UserRole>()?;
-
-        if let Err(e) = Validator::validate_username(&username) {
-            return u.conn().send::<Result<(), ErrorMessage>>(&Err(e));
-        } else if let Err(e) = Validator::validate_password(&password) {
-            return u.conn().send::<Result<(), ErrorMessage>>(&Err(e));
-        } else if let Err(e) = Validator::validate_phone_number(&phone) {
-            return u.conn().send::<Result<(), ErrorMessage>>(&Err(e));
-        }
-
-        let current_user = u.user_account()?;
-
-        let res = match u
-            .ac
-            .enforce(Request::new(&current_user, AccessObject::AddUser))?
-        {
-            true => {
-                if Database::get(&username)?.is_some() {
-                    warn!("User {} already exists", username);
-                    Err(ErrorMessage::ErrorUserAlreadyExists)
-                } else {
-                    info!("Adding user {}", username);
-                    let user = UserAccount::new(username, password, phone, role);
-                    Database::insert(&user)?;
-                    Ok(())
-                }
+                let revoked = u
+                    .sessions
+                    .revoke_user(user.username(), u.session.as_deref());
+                info!(
+                    "User {} changed its password, {} other session(s) revoked",
+                    user.username(),
+                    revoked
+                );
+                u.audit(
+                    AuditEvent::PasswordChanged,
+                    Some(user.username()),
+                    format!("{} other session(s) revoked", revoked),
+                )?;
+                Ok(())
             }
-            false => Err(ErrorMessage::ErrorNotAuthorized),
-        };
+        }
+    };
 
-        u.conn.send(&res)
+    Ok(res.into())
+}
+
+fn reset_password(u: &mut ConnectedUser, target: String) -> Result<Response, Box<dyn Error>> {
+    trace!("Reset password");
+
+    if let Err(e) = Validator::validate_username(&target) {
+        return Ok(e.into());
     }
 
-    pub fn login(u: &mut ConnectedUser) -> Result<(), Box<dyn Error>> {
-        trace!("Login");
-        // Receive data
-        let username = u.conn().receive::<String>()?;
-        let password = u.conn().receive::<String>()?;
+    let res = match u.store.get(&target)? {
+        None => {
+            warn!("User {} not found", target);
+            Err(ErrorMessage::ErrorUserNotFound)
+        }
+        Some(t) if !u.is_authorized(AccessObject::ResetPassword, Some(&t))? => {
+            warn!("{} tried to reset the password of {}", u.name(), target);
+            Err(ErrorMessage::ErrorNotAuthorized)
+        }
+        Some(mut t) => {
+            let temporary = password::generate_temporary();
+            t.reset_password(&temporary)?;
+            if u.store.update(&t)? {
+                // Whoever used the old password is logged out, and the
+                // owner can log in right away
+                u.sessions.revoke_user(&target, None);
+                u.throttle.unlock(&target);
+                info!("{} reset the password of {}", u.name(), target);
+                u.audit(AuditEvent::PasswordReset, Some(&target), String::new())?;
+                Ok(temporary)
+            } else {
+                Err(conflict(&t))
+            }
+        }
+    };
+
+    Ok(res.map_or_else(Response::Error, Response::TemporaryPassword))
+}
+
+/// Sends a new secret to the user, it is only enabled once confirmed by
+/// `confirm_two_factor`
+fn enable_two_factor(u: &mut ConnectedUser) -> Result<Response, Box<dyn Error>> {
+    trace!("Enable two-factor authentication");
+
+    // Check permissions
+    let user = if u.is_anonymous() {
+        return Ok(ErrorMessage::ErrorNotLoggedIn.into());
+    } else {
+        let user = u.user_account()?;
+        if !u.is_authorized(AccessObject::EnableTwoFactor, Some(&user))? {
+            warn!("{} tried to enable two-factor authentication", u.name());
+            return Ok(ErrorMessage::ErrorNotAuthorized.into());
+        } else if user.has_two_factor() {
+            return Ok(ErrorMessage::ErrorTwoFactorAlreadyEnabled.into());
+        }
+        user
+    };
+
+    let secret = two_factor::generate_secret();
+    let recovery_codes = two_factor::generate_recovery_codes();
+    let setup = TwoFactorSetup {
+        uri: two_factor::otpauth_uri(&secret, user.username())?,
+        secret: secret.clone(),
+        recovery_codes: recovery_codes.clone(),
+    };
+    u.pending_two_factor = Some((secret, recovery_codes));
+    Ok(Response::TwoFactorSetup(setup))
+}
 
-        if let Err(s) = Validator::validate_username(&username) {
-            return u.conn().send::<Result<(), ErrorMessage>>(&Err(s));
-        } else if let Err(s) = Validator::validate_password(&password) {
-            return u.conn().send::<Result<(), ErrorMessage>>(&Err(s));
+/// Enables the secret sent by the previous request, once the user proved its
+/// app generates valid codes
+fn confirm_two_factor(
+    u: &mut ConnectedUser,
+    pending: Option<(String, Vec<String>)>,
+    code: String,
+) -> Result<Response, Box<dyn Error>> {
+    trace!("Confirm two-factor authentication");
+
+    let (secret, recovery_codes) = match pending {
+        Some(pending) if !u.is_anonymous() => pending,
+        _ => {
+            warn!("Two-factor confirmation sent without a pending setup");
+            return Ok(ErrorMessage::ErrorInvalidCode.into());
         }
+    };
+    let mut user = u.user_account()?;
 
-        let res = if !u.is_anonymous() {
-            info!("User already logged in");
-            Err(ErrorMessage::ErrorIsLoggedIn)
+    let res = if let Err(e) = Validator::validate_auth_code(&code) {
+        Err(e)
+    } else if user.has_two_factor() {
+        Err(ErrorMessage::ErrorTwoFactorAlreadyEnabled)
+    } else if let Some(step) = two_factor::check_code(&secret, user.username(), &code, None)? {
+        user.enable_two_factor(secret, step, &recovery_codes);
+        if u.store.update(&user)? {
+            info!("User {} enabled two-factor authentication", user.username());
+            u.audit(
+                AuditEvent::TwoFactorEnabled,
+                Some(user.username()),
+                String::new(),
+            )?;
+            Ok(())
         } else {
-            let user = Database::get(&username)?;
-            if let Some(user) = user {
-                if user.password() == password {
-                    u.set_username(&username);
-                    info!("User {} logged in", username);
-                    Ok(())
-                } else {
-                    warn!("Wrong password for user {}", username);
-                    Err(ErrorMessage::ErrorLogin)
-                }
-            } else {
-                warn!("User {} not found", username);
-                Err(ErrorMessage::ErrorLogin)
+            Err(conflict(&user))
+        }
+    } else {
+        warn!(
+            "Wrong code while enabling two-factor authentication for {}",
+            user.username()
+        );
+        Err(ErrorMessage::ErrorInvalidCode)
+    };
+
+    Ok(res.into())
+}
+
+fn logout(u: &mut ConnectedUser) -> Result<Response, Box<dyn Error>> {
+    trace!("Logout");
+    // Check permissions
+    let res = if u.is_anonymous() {
+        debug!("User not logged in");
+        Err(ErrorMessage::ErrorNotLoggedIn)
+    } else {
+        info!("User {} logged out", u.username());
+        u.audit(AuditEvent::Logout, None, String::new())?;
+        u.logout();
+        Ok(())
+    };
+
+    Ok(res.into())
+}
+
+fn resume_session(u: &mut ConnectedUser, token: String) -> Result<Response, Box<dyn Error>> {
+    trace!("Resume session");
+
+    let res = if !u.is_anonymous() {
+        info!("User already logged in");
+        Err(ErrorMessage::ErrorIsLoggedIn)
+    } else {
+        match u.sessions.resume(&token) {
+            Some(username) if matches!(u.store.get(&username)?, Some(t) if t.is_active()) => {
+                info!("User {} resumed its session", username);
+                u.set_username(&username);
+                u.session = Some(token);
+                Ok(())
             }
-        };
+            _ => {
+                warn!("Invalid or expired session token");
+                Err(ErrorMessage::ErrorInvalidSession)
+            }
+        }
+    };
+
+    Ok(res.into())
+}
 
-        u.conn.send(&res)
+fn unlock_user(u: &mut ConnectedUser, target: String) -> Result<Response, Box<dyn Error>> {
+    trace!("Unlock user");
+
+    if let Err(e) = Validator::validate_username(&target) {
+        return Ok(e.into());
     }
 
-    pub fn logout(u: &mut ConnectedUser) -> Result<(), Box<dyn Error>> {
-        trace!("Logout");
-        let res: Result<(), ErrorMessage>;
+    let res = match u.store.get(&target)? {
+        None => {
+            warn!("User {} not found", target);
+            Err(ErrorMessage::ErrorUserNotFound)
+        }
+        Some(t) if !u.is_authorized(AccessObject::UnlockUser, Some(&t))? => {
+            warn!("{} tried to unlock user {}", u.name(), target);
+            Err(ErrorMessage::ErrorNotAuthorized)
+        }
+        Some(_) => {
+            if u.throttle.unlock(&target) {
+                info!("{} unlocked user {}", u.name(), target);
+                u.audit(AuditEvent::UserUnlocked, Some(&target), String::new())?;
+            } else {
+                info!("User {} was not locked", target);
+            }
+            Ok(())
+        }
+    };
 
-        // Check permissions
-        res = if u.is_anonymous() {
-            debug!("User not logged in");
-            Err(ErrorMessage::ErrorNotLoggedIn)
-        } else {
-            info!("User {} logged out", u.username());
-            u.logout();
+    Ok(res.into())
+}
+
+/// Deactivates or reactivates an account. A deactivated account is kept,
+/// but its sessions are closed and it cannot log in anymore.
+fn set_user_active(
+    u: &mut ConnectedUser,
+    target: String,
+    active: bool,
+) -> Result<Response, Box<dyn Error>> {
+    trace!("Set user active: {}", active);
+
+    if let Err(e) = Validator::validate_username(&target) {
+        return Ok(e.into());
+    }
+
+    let (object, verb) = if active {
+        (AccessObject::ReactivateUser, "reactivate")
+    } else {
+        (AccessObject::DeactivateUser, "deactivate")
+    };
+    let res = match u.store.get(&target)? {
+        None => {
+            warn!("User {} not found", target);
+            Err(ErrorMessage::ErrorUserNotFound)
+        }
+        Some(t) if !u.is_authorized(object.clone(), Some(&t))? => {
+            warn!("{} tried to {} user {}", u.name(), verb, target);
+            Err(ErrorMessage::ErrorNotAuthorized)
+        }
+        Some(t) if t.is_active() == active => {
+            info!("No need to {} user {}", verb, target);
             Ok(())
-        };
+        }
+        Some(t) => match u.ac.set_active(u.store.as_ref(), &t, active)? {
+            Err(ErrorMessage::ErrorLastHR) => {
+                warn!("{} tried to deactivate {}, the last HR", u.name(), target);
+                Err(ErrorMessage::ErrorLastHR)
+            }
+            Err(_) => Err(conflict(&t)),
+            Ok(()) if active => {
+                info!("{} reactivated user {}", u.name(), target);
+                u.audit(AuditEvent::UserReactivated, Some(&target), String::new())?;
+                Ok(())
+            }
+            Ok(()) => {
+                let revoked = u.sessions.revoke_user(&target, None);
+                info!(
+                    "{} deactivated user {}, {} session(s) revoked",
+                    u.name(),
+                    target,
+                    revoked
+                );
+                u.audit(
+                    AuditEvent::UserDeactivated,
+                    Some(&target),
+                    format!("{} session(s) revoked", revoked),
+                )?;
+                Ok(())
+            }
+        },
+    };
+
+    Ok(res.into())
+}
+
+fn delete_user(u: &mut ConnectedUser, target: String) -> Result<Response, Box<dyn Error>> {
+    trace!("Delete user");
+
+    if let Err(e) = Validator::validate_username(&target) {
+        return Ok(e.into());
+    }
+
+    let res = match u.store.get(&target)? {
+        None => {
+            warn!("User {} not found", target);
+            Err(ErrorMessage::ErrorUserNotFound)
+        }
+        Some(t) if !u.is_authorized(AccessObject::DeleteUser, Some(&t))? => {
+            warn!("{} tried to delete user {}", u.name(), target);
+            Err(ErrorMessage::ErrorNotAuthorized)
+        }
+        Some(_) => match u.ac.delete_user(u.store.as_ref(), &target)? {
+            Ok(t) => {
+                let revoked = u.sessions.revoke_user(&target, None);
+                u.throttle.unlock(&target);
+                info!(
+                    "{} deleted user {}, {} session(s) revoked",
+                    u.name(),
+                    target,
+                    revoked
+                );
+                u.audit(
+                    AuditEvent::UserDeleted,
+                    Some(&target),
+                    format!("role {}", t.role()),
+                )?;
+                Ok(())
+            }
+            Err(ErrorMessage::ErrorLastHR) => {
+                warn!("{} tried to delete {}, the last HR", u.name(), target);
+                Err(ErrorMessage::ErrorLastHR)
+            }
+            Err(e) => {
+                warn!("User {} was deleted in the meantime", target);
+                Err(e)
+            }
+        },
+    };
+
+    Ok(res.into())
+}
+
+fn change_role(
+    u: &mut ConnectedUser,
+    target: String,
+    role: UserRole,
+) -> Result<Response, Box<dyn Error>> {
+    trace!("Change role");
+
+    if let Err(e) = Validator::validate_username(&target) {
+        return Ok(e.into());
+    }
+
+    let res = match u.store.get(&target)? {
+        None => {
+            warn!("User {} not found", target);
+            Err(ErrorMessage::ErrorUserNotFound)
+        }
+        Some(t) => set_role(u, &t, role)?,
+    };
+
+    Ok(res.into())
+}
 
-        u.conn.send(&res)
+/// Checks and applies a role change, shared by ChangeRole and RevertChange
+fn set_role(
+    u: &mut ConnectedUser,
+    t: &UserAccount,
+    role: UserRole,
+) -> Result<Result<(), ErrorMessage>, Box<dyn Error>> {
+    let target = t.username();
+    if !u.is_authorized(AccessObject::ChangeRole, Some(t))? {
+        warn!("{} tried to change the role of {}", u.name(), target);
+        return Ok(Err(ErrorMessage::ErrorNotAuthorized));
+    } else if role == UserRole::Anon {
+        // Only given to users who are not logged in
+        warn!("{} tried to give the role {} to {}", u.name(), role, target);
+        return Ok(Err(ErrorMessage::ErrorNotAuthorized));
+    } else if *t.role() == role {
+        info!("User {} already has the role {}", target, role);
+        return Ok(Ok(()));
     }
+
+    let res = u.ac.change_role(u.store.as_ref(), t, role, &u.name())?;
+    match &res {
+        Ok(_) => {
+            info!(
+                "{} changed the role of {} from {} to {}",
+                u.name(),
+                target,
+                t.role(),
+                role
+            );
+            u.audit(
+                AuditEvent::RoleChanged,
+                Some(target),
+                format!("{} -> {}", t.role(), role),
+            )?;
+        }
+        Err(ErrorMessage::ErrorLastHR) => {
+            warn!("{} tried to demote {}, the last HR", u.name(), target)
+        }
+        Err(_) => warn!("Role of {} was changed concurrently", target),
+    }
+    Ok(res)
+}
+
+fn show_audit_log(u: &mut ConnectedUser, query: AuditQuery) -> Result<Response, Box<dyn Error>> {
+    trace!("Show audit log");
+
+    // Logged-out users are recorded as `ANONYMOUS`, which may be looked for
+    let actor = query.actor.iter().filter(|a| *a != ANONYMOUS);
+    let res = if let Err(e) = validate_usernames(actor.chain(query.target.iter())) {
+        Err(e)
+    } else if !u.is_authorized(AccessObject::ShowAuditLog, None)? {
+        warn!("{} tried to read the audit log", u.name());
+        Err(ErrorMessage::ErrorNotAuthorized)
+    } else {
+        debug!("{} reads the audit log: {:?}", u.name(), query);
+        u.audit_log.query(&query)?
+    };
+
+    Ok(res.map_or_else(Response::Error, Response::AuditLog))
+}
+
+fn show_user_history(u: &mut ConnectedUser, target: String) -> Result<Response, Box<dyn Error>> {
+    trace!("Show user history");
+
+    if let Err(e) = Validator::validate_username(&target) {
+        return Ok(e.into());
+    }
+
+    let res = match u.store.get(&target)? {
+        None => {
+            warn!("User {} not found", target);
+            Err(ErrorMessage::ErrorUserNotFound)
+        }
+        Some(t) if !u.is_authorized(AccessObject::ShowUserHistory, Some(&t))? => {
+            warn!("{} tried to read the history of {}", u.name(), target);
+            Err(ErrorMessage::ErrorNotAuthorized)
+        }
+        Some(t) => Ok(t.revisions().iter().map(AccountRevision::from).collect()),
+    };
+
+    Ok(res.map_or_else(Response::Error, Response::History))
+}
+
+/// Restores the value a field had before a revision. The change goes
+/// through the same checks as if it was made by hand.
+fn revert_change(
+    u: &mut ConnectedUser,
+    target: String,
+    version: u64,
+) -> Result<Response, Box<dyn Error>> {
+    trace!("Revert change");
+
+    if let Err(e) = Validator::validate_username(&target) {
+        return Ok(e.into());
+    }
+
+    let (mut t, revision) = match u.store.get(&target)? {
+        None => {
+            warn!("User {} not found", target);
+            return Ok(ErrorMessage::ErrorUserNotFound.into());
+        }
+        Some(t) if !u.is_authorized(AccessObject::RevertChange, Some(&t))? => {
+            warn!("{} tried to revert a change of {}", u.name(), target);
+            return Ok(ErrorMessage::ErrorNotAuthorized.into());
+        }
+        Some(t) => match t.revisions().iter().find(|r| r.version == version) {
+            Some(r) => {
+                let r = r.clone();
+                (t, r)
+            }
+            None => {
+                warn!("Revision {} of {} not found", version, target);
+                return Ok(ErrorMessage::ErrorRevisionNotFound.into());
+            }
+        },
+    };
+
+    info!(
+        "{} reverts the {} of {} to {}",
+        u.name(),
+        revision.field,
+        target,
+        revision.old
+    );
+    match revision.field {
+        AccountField::PhoneNumber => change_phone(u, &mut t, revision.old, None),
+        AccountField::Role => {
+            let res = match UserRole::from_str(&revision.old) {
+                Ok(role) => set_role(u, &t, role)?,
+                Err(_) => Err(ErrorMessage::ErrorRevisionNotFound),
+            };
+            Ok(res.into())
+        }
+    }
+}
+
+/// A write was rejected because the account changed since it was read
+fn conflict(user: &UserAccount) -> ErrorMessage {
+    warn!("Account {} was changed concurrently", user.username());
+    ErrorMessage::ErrorConflict(String::new())
 }
 
 /// Used to represent a connected user for the actions
 pub struct ConnectedUser {
     pub username: Option<String>,
+    session: Option<String>,
+    // Set when the session expired and the client has not been told yet
+    session_expired: bool,
+    // User whose password was verified, waiting for its second factor
+    pending_login: Option<String>,
+    // Secret and recovery codes sent to the user, waiting for a valid code
+    pending_two_factor: Option<(String, Vec<String>)>,
+    store: Arc<dyn UserStore>,
     ac: Arc<AccessController>,
+    throttle: Arc<Throttle>,
+    sessions: Arc<SessionManager>,
+    audit_log: Arc<AuditLog>,
     pub conn: Connection,
 }
 
 impl ConnectedUser {
-    pub fn anonymous(ac: Arc<AccessController>, conn: Connection) -> ConnectedUser {
+    pub fn anonymous(
+        store: Arc<dyn UserStore>,
+        ac: Arc<AccessController>,
+        throttle: Arc<Throttle>,
+        sessions: Arc<SessionManager>,
+        audit_log: Arc<AuditLog>,
+        conn: Connection,
+    ) -> ConnectedUser {
         ConnectedUser {
             username: None,
+            session: None,
+            session_expired: false,
+            pending_login: None,
+            pending_two_factor: None,
+            store,
             ac,
+            throttle,
+            sessions,
+            audit_log,
             conn,
         }
     }
@@ -255,19 +1015,384 @@ impl ConnectedUser {
     }
 
     pub fn is_anonymous(&self) -> bool {
-        return self.username.is_none();
+        self.username.is_none()
+    }
+
+    /// Logs the user in and returns the token of its new session
+    pub fn start_session(&mut self, username: &str) -> String {
+        let token = self.sessions.create(username);
+        self.set_username(username);
+        self.session = Some(token.clone());
+        token
     }
 
     pub fn logout(&mut self) {
+        if let Some(token) = self.session.take() {
+            self.sessions.revoke(&token);
+        }
         self.username = None;
     }
 
+    /// Logs the user out because its session expired
+    pub fn expire_session(&mut self) {
+        info!("Session of {} expired", self.name());
+        self.logout();
+        self.session_expired = true;
+    }
+
+    /// Called before each action, fails if the session of the user expired
+    /// since the last one
+    pub fn check_session(&mut self) -> Result<(), ErrorMessage> {
+        if let Some(token) = &self.session {
+            if self.sessions.resume(token).is_none() {
+                self.expire_session();
+            }
+        }
+
+        if self.session_expired {
+            self.session_expired = false;
+            Err(ErrorMessage::ErrorSessionExpired)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Name used in the logs, `ANONYMOUS` if not logged in
+    pub fn name(&self) -> String {
+        self.username
+            .clone()
+            .unwrap_or_else(|| ANONYMOUS.to_string())
+    }
+
+    /// Adds a record performed by this user to the audit log
+    pub fn audit(
+        &self,
+        event: AuditEvent,
+        target: Option<&str>,
+        details: String,
+    ) -> Result<(), Box<dyn Error>> {
+        self.audit_log.record(&self.name(), event, target, details)
+    }
+
+    fn audit_login_failure(
+        &self,
+        username: &str,
+        addr: IpAddr,
+        reason: &str,
+    ) -> Result<(), Box<dyn Error>> {
+        self.audit(
+            AuditEvent::LoginFailure,
+            Some(username),
+            format!("from {}: {}", addr, reason),
+        )
+    }
+
+    /// Whether the user may perform the action on the target account, if any.
+    /// Anonymous users are never authorized. Denials are audited.
+    pub fn is_authorized(
+        &mut self,
+        object: AccessObject,
+        target: Option<&UserAccount>,
+    ) -> Result<bool, Box<dyn Error>> {
+        self.is_authorized_on(object, target.map(|t| (t.username(), *t.role())))
+    }
+
+    /// Same as `is_authorized`, for a target account given by its username
+    /// and role, which may not exist yet
+    pub fn is_authorized_on(
+        &mut self,
+        object: AccessObject,
+        target: Option<(&str, UserRole)>,
+    ) -> Result<bool, Box<dyn Error>> {
+        let allowed = if self.is_anonymous() {
+            false
+        } else {
+            let user = self.user_account()?;
+            let req = match target {
+                Some(t) => AccessRequest::with_target(&user, object.clone(), t),
+                None => AccessRequest::new(&user, object.clone()),
+            };
+            self.ac.enforce(req)?
+        };
+
+        if !allowed {
+            self.audit(
+                AuditEvent::AccessDenied,
+                target.map(|(name, _)| name),
+                object.to_string(),
+            )?;
+        }
+        Ok(allowed)
+    }
+
+    pub fn must_change_password(&mut self) -> Result<bool, Box<dyn Error>> {
+        if self.is_anonymous() {
+            return Ok(false);
+        }
+        Ok(self.user_account()?.must_change_password())
+    }
+
+    /// Whether the role of the user requires two-factor authentication and it
+    /// is not enabled yet
+    pub fn must_enable_two_factor(&mut self) -> Result<bool, Box<dyn Error>> {
+        if self.is_anonymous() {
+            return Ok(false);
+        }
+
+        let user = self.user_account()?;
+        Ok(!user.has_two_factor()
+            && self
+                .ac
+                .enforce(AccessRequest::new(&user, AccessObject::RequireTwoFactor))?)
+    }
+
     pub fn user_account(&mut self) -> Result<UserAccount, Box<dyn Error>> {
         if self.is_anonymous() {
             // Je n'arrive pas à faire plus gracieux…
             Err(ErrorMessage::ErrorNotLoggedIn.into())
         } else {
-            Ok(Database::get(&self.username())?.expect("User logged in but not in DB"))
+            let username = self.username();
+            // Deleted while logged in, the session is closed before the
+            // next action
+            self.store
+                .get(&username)?
+                .ok_or_else(|| ErrorMessage::ErrorUserNotFound.into())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::MemoryStore;
+    use crate::session::SessionConfig;
+    use crate::testing::{account, TestPolicy};
+    use crate::throttle::FREE_ATTEMPTS;
+    use std::net::{TcpListener, TcpStream};
+    use std::path::Path;
+    use std::sync::Barrier;
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    const RUNS: usize = 7;
+
+    /// A user connected over TCP from within the test, the client end of the
+    /// connection is returned so it stays open
+    fn connect(
+        store: Arc<dyn UserStore>,
+        ac: Arc<AccessController>,
+        throttle: Arc<Throttle>,
+        audit_log: Arc<AuditLog>,
+    ) -> (ConnectedUser, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (server, _) = listener.accept().unwrap();
+        let u = ConnectedUser::anonymous(
+            store,
+            ac,
+            throttle,
+            Arc::new(SessionManager::new(SessionConfig::from_env())),
+            audit_log,
+            Connection::plain(server),
+        );
+        (u, client)
+    }
+
+    fn audit_log(dir: &Path) -> Arc<AuditLog> {
+        let path = dir.join("audit.log");
+        Arc::new(AuditLog::open(path.to_str().unwrap()).unwrap())
+    }
+
+    fn wrong_login(username: &str) -> Request {
+        Request::Login {
+            username: username.to_string(),
+            password: "Wr0ng*pass".to_string(),
         }
     }
+
+    fn median_time(u: &mut ConnectedUser, username: &str) -> Duration {
+        let mut times: Vec<Duration> = (0..RUNS)
+            .map(|_| {
+                // The failures of the previous runs would delay the login
+                u.throttle = Arc::new(Throttle::new());
+
+                let start = Instant::now();
+                let response = perform(u, wrong_login(username)).unwrap();
+                let elapsed = start.elapsed();
+                assert!(matches!(
+                    response,
+                    Response::Error(ErrorMessage::ErrorLogin)
+                ));
+                elapsed
+            })
+            .collect();
+        times.sort();
+        times[RUNS / 2]
+    }
+
+    fn contains(haystack: &[u8], needle: &str) -> bool {
+        haystack
+            .windows(needle.len())
+            .any(|w| w == needle.as_bytes())
+    }
+
+    #[test]
+    fn user_list_has_no_secret() {
+        let recovery_codes = two_factor::generate_recovery_codes();
+        let mut accounts = vec![
+            account("anne", UserRole::StandardUser),
+            account("harry", UserRole::HR),
+        ];
+        for user in &mut accounts {
+            let secret = two_factor::generate_secret();
+            user.enable_two_factor(secret, 0, &recovery_codes);
+        }
+        let store = MemoryStore::new(accounts.clone());
+
+        let policy = TestPolicy::new(&store);
+        let ac = &policy.ac;
+        let throttle = Throttle::new();
+
+        let secrets: Vec<String> = accounts
+            .iter()
+            .flat_map(|u| {
+                let record = crate::user::AccountRecord::from(u);
+                let mut secrets = vec![record.password_hash];
+                secrets.extend(record.totp_secret);
+                secrets.extend(record.recovery_codes);
+                secrets
+            })
+            .chain(
+                recovery_codes
+                    .iter()
+                    .map(|c| two_factor::hash_recovery_code(c)),
+            )
+            .collect();
+
+        for caller in [None, Some(&accounts[0]), Some(&accounts[1])] {
+            let users = list_users(&store, ac, &throttle, caller).unwrap();
+            assert_eq!(users.len(), accounts.len());
+            if caller == Some(&accounts[1]) {
+                // Otherwise the test would pass with an empty listing
+                assert!(users
+                    .iter()
+                    .all(|u| u.phone_number.is_some() && u.status.is_some()));
+            }
+
+            let wire = bincode::serialize(&Response::Users(users)).unwrap();
+            for secret in &secrets {
+                assert!(!contains(&wire, secret), "{} was sent", secret);
+            }
+        }
+    }
+
+    #[test]
+    fn unknown_username_takes_as_long_as_wrong_password() {
+        let store = Arc::new(MemoryStore::new(vec![account(
+            "alice",
+            UserRole::StandardUser,
+        )]));
+        let policy = TestPolicy::new(store.as_ref());
+        let dir = tempfile::tempdir().unwrap();
+        let (mut u, _client) = connect(
+            store,
+            policy.ac.clone(),
+            Arc::new(Throttle::new()),
+            audit_log(dir.path()),
+        );
+        password::init();
+
+        // Alternated so both are measured under the same load
+        let (mut known, mut unknown) = (Duration::ZERO, Duration::ZERO);
+        for _ in 0..3 {
+            known += median_time(&mut u, "alice");
+            unknown += median_time(&mut u, "mallory");
+        }
+
+        let ratio = known.as_secs_f64() / unknown.as_secs_f64();
+        assert!(
+            (0.8..1.25).contains(&ratio),
+            "known user: {:?}, unknown user: {:?}",
+            known,
+            unknown
+        );
+    }
+
+    #[test]
+    fn parallel_logins_are_throttled() {
+        const LOGINS: usize = 10;
+        let store = Arc::new(MemoryStore::new(vec![account(
+            "alice",
+            UserRole::StandardUser,
+        )]));
+        let policy = TestPolicy::new(store.as_ref());
+        let dir = tempfile::tempdir().unwrap();
+        let audit_log = audit_log(dir.path());
+        let throttle = Arc::new(Throttle::new());
+        password::init();
+
+        let start = Arc::new(Barrier::new(LOGINS));
+        let logins: Vec<_> = (0..LOGINS)
+            .map(|_| {
+                let (mut u, client) = connect(
+                    store.clone(),
+                    policy.ac.clone(),
+                    throttle.clone(),
+                    audit_log.clone(),
+                );
+                let start = start.clone();
+                thread::spawn(move || {
+                    let _client = client;
+                    start.wait();
+                    perform(&mut u, wrong_login("alice")).unwrap()
+                })
+            })
+            .collect();
+
+        // Only the attempts allowed one after the other get their password
+        // verified, the others are turned down
+        let verified = logins
+            .into_iter()
+            .map(|login| login.join().unwrap())
+            .filter(|r| matches!(r, Response::Error(ErrorMessage::ErrorLogin)))
+            .count();
+        assert!(
+            verified <= FREE_ATTEMPTS as usize,
+            "{} passwords verified",
+            verified
+        );
+    }
+
+    #[test]
+    fn anonymous_phone_change_is_audited() {
+        let store = Arc::new(MemoryStore::new(vec![account(
+            "alice",
+            UserRole::StandardUser,
+        )]));
+        let policy = TestPolicy::new(store.as_ref());
+        let dir = tempfile::tempdir().unwrap();
+        let audit_log = audit_log(dir.path());
+        let (mut u, _client) = connect(
+            store,
+            policy.ac.clone(),
+            Arc::new(Throttle::new()),
+            audit_log.clone(),
+        );
+
+        let request = Request::ChangeOwnPhone {
+            phone_number: "079-111-3333".to_string(),
+        };
+        assert!(matches!(
+            perform(&mut u, request).unwrap(),
+            Response::Error(ErrorMessage::ErrorNotAuthorized)
+        ));
+
+        let query = AuditQuery {
+            actor: Some(ANONYMOUS.to_string()),
+            event: Some(AuditEvent::AccessDenied.to_string()),
+            ..Default::default()
+        };
+        let page = audit_log.query(&query).unwrap().unwrap();
+        assert_eq!(page.entries.len(), 1);
+    }
 }
@@ -0,0 +1,310 @@
+/// This file is used to keep a tamper-evident trail of the security events
+///
+/// Each record is a line of JSON in `audit.log` holding the SHA-256 of the
+/// previous record, so changing or removing a record breaks the chain from
+/// there on. `lab3_server verify-audit` checks the chain. Removing the last
+/// records leaves a valid chain, which is why the head (number of records and
+/// last hash) is logged on startup and printed by the verifier, to be compared
+/// with a copy kept elsewhere.
+use protocol::{AuditEntry, AuditPage, AuditQuery};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::error::Error;
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+use strum_macros::{Display, EnumString};
+use utils::ErrorMessage;
+
+pub static AUDIT_PATH: &str = "audit.log";
+// Previous hash of the first record
+const GENESIS: &str = "0000000000000000000000000000000000000000000000000000000000000000";
+const PAGE_SIZE: u64 = 20;
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Display, EnumString, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum AuditEvent {
+    #[strum(serialize = "login_success")]
+    LoginSuccess,
+    #[strum(serialize = "login_failure")]
+    LoginFailure,
+    #[strum(serialize = "logout")]
+    Logout,
+    #[strum(serialize = "access_denied")]
+    AccessDenied,
+    #[strum(serialize = "user_created")]
+    UserCreated,
+    #[strum(serialize = "phone_changed")]
+    PhoneChanged,
+    #[strum(serialize = "password_changed")]
+    PasswordChanged,
+    #[strum(serialize = "password_reset")]
+    PasswordReset,
+    #[strum(serialize = "two_factor_enabled")]
+    TwoFactorEnabled,
+    #[strum(serialize = "user_unlocked")]
+    UserUnlocked,
+    #[strum(serialize = "user_deactivated")]
+    UserDeactivated,
+    #[strum(serialize = "user_reactivated")]
+    UserReactivated,
+    #[strum(serialize = "user_deleted")]
+    UserDeleted,
+    #[strum(serialize = "role_changed")]
+    RoleChanged,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct AuditRecord {
+    pub seq: u64,
+    // Seconds since the Unix epoch
+    pub time: u64,
+    // Who performed the action, `ANONYMOUS` if not logged in
+    pub actor: String,
+    pub event: AuditEvent,
+    // The account the action was performed on, if any
+    pub target: Option<String>,
+    pub details: String,
+    pub prev_hash: String,
+    pub hash: String,
+}
+
+impl AuditRecord {
+    /// Hash of every other field, including the hash of the previous record
+    fn compute_hash(&self) -> String {
+        let content = serde_json::to_vec(&(
+            self.seq,
+            self.time,
+            &self.actor,
+            self.event,
+            &self.target,
+            &self.details,
+            &self.prev_hash,
+        ))
+        .expect("Audit records can always be serialized");
+        hex::encode(Sha256::digest(content))
+    }
+
+    fn matches(&self, query: &AuditQuery, event: Option<AuditEvent>) -> bool {
+        query.actor.as_ref().is_none_or(|a| *a == self.actor)
+            && query
+                .target
+                .as_ref()
+                .is_none_or(|t| Some(t) == self.target.as_ref())
+            && event.is_none_or(|e| e == self.event)
+            && query.since.is_none_or(|s| self.time >= s)
+            && query.until.is_none_or(|u| self.time < u)
+    }
+}
+
+impl From<AuditRecord> for AuditEntry {
+    fn from(r: AuditRecord) -> Self {
+        Self {
+            seq: r.seq,
+            time: r.time,
+            actor: r.actor,
+            event: r.event.to_string(),
+            target: r.target,
+            details: r.details,
+        }
+    }
+}
+
+/// End of the chain, where the next record is appended
+#[derive(Clone, Debug)]
+pub struct Head {
+    pub count: u64,
+    pub hash: String,
+}
+
+impl Head {
+    fn genesis() -> Self {
+        Self {
+            count: 0,
+            hash: GENESIS.to_string(),
+        }
+    }
+}
+
+pub struct AuditLog {
+    path: PathBuf,
+    state: Mutex<(File, Head)>,
+}
+
+impl AuditLog {
+    /// Opens the log, creating it if needed. The existing records are checked
+    /// first, new records are never chained to a log that was tampered with.
+    pub fn open(path: &str) -> Result<Self, Box<dyn Error>> {
+        let head = if Path::new(path).exists() {
+            verify(path)?
+        } else {
+            Head::genesis()
+        };
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(Self {
+            path: PathBuf::from(path),
+            state: Mutex::new((file, head)),
+        })
+    }
+
+    pub fn head(&self) -> Head {
+        self.state.lock().unwrap().1.clone()
+    }
+
+    /// Appends a record, it is on disk when this returns
+    pub fn record(
+        &self,
+        actor: &str,
+        event: AuditEvent,
+        target: Option<&str>,
+        details: String,
+    ) -> Result<(), Box<dyn Error>> {
+        let mut state = self.state.lock().unwrap();
+        let (file, head) = &mut *state;
+
+        let mut record = AuditRecord {
+            seq: head.count,
+            time: SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs(),
+            actor: actor.to_string(),
+            event,
+            target: target.map(str::to_string),
+            details,
+            prev_hash: head.hash.clone(),
+            hash: String::new(),
+        };
+        record.hash = record.compute_hash();
+
+        let mut line = serde_json::to_string(&record)?;
+        line.push('\n');
+        file.write_all(line.as_bytes())?;
+        file.sync_data()?;
+
+        *head = Head {
+            count: head.count + 1,
+            hash: record.hash,
+        };
+        Ok(())
+    }
+
+    /// One page of the records matching the query, most recent first
+    pub fn query(
+        &self,
+        query: &AuditQuery,
+    ) -> Result<Result<AuditPage, ErrorMessage>, Box<dyn Error>> {
+        let event = match query.event.as_deref().map(AuditEvent::from_str).transpose() {
+            Ok(event) => event,
+            Err(_) => return Ok(Err(ErrorMessage::ErrorInvalidFilter)),
+        };
+        if matches!((query.since, query.until), (Some(s), Some(u)) if s >= u) {
+            return Ok(Err(ErrorMessage::ErrorInvalidFilter));
+        }
+        let skip = match query
+            .page
+            .checked_mul(PAGE_SIZE)
+            .and_then(|s| usize::try_from(s).ok())
+        {
+            Some(skip) => skip,
+            None => return Ok(Err(ErrorMessage::ErrorInvalidFilter)),
+        };
+
+        // Locked so no record is read while it is being written
+        let _state = self.state.lock().unwrap();
+        let mut records = Vec::new();
+        for line in BufReader::new(File::open(&self.path)?).lines() {
+            let record: AuditRecord = serde_json::from_str(&line?)?;
+            if record.matches(query, event) {
+                records.push(record);
+            }
+        }
+
+        let pages = (records.len() as u64).div_ceil(PAGE_SIZE);
+        // Page 0 is always there, empty when nothing matches
+        if query.page >= pages.max(1) {
+            return Ok(Err(ErrorMessage::ErrorInvalidFilter));
+        }
+        let entries = records
+            .into_iter()
+            .rev()
+            .skip(skip)
+            .take(PAGE_SIZE as usize)
+            .map(AuditEntry::from)
+            .collect();
+        Ok(Ok(AuditPage {
+            entries,
+            page: query.page,
+            pages,
+        }))
+    }
+}
+
+/// Checks every record of the log and its link to the previous one, returns
+/// the head of the chain
+pub fn verify(path: &str) -> Result<Head, Box<dyn Error>> {
+    let file = File::open(path).map_err(|e| format!("Could not open {}: {}", path, e))?;
+    let mut head = Head::genesis();
+
+    for (i, line) in BufReader::new(file).lines().enumerate() {
+        let line = line?;
+        let record: AuditRecord = serde_json::from_str(&line)
+            .map_err(|e| format!("Record {} of {} is unreadable: {}", i + 1, path, e))?;
+
+        if record.seq != head.count || record.prev_hash != head.hash {
+            return Err(format!(
+                "Record {} of {} does not follow the previous one, records were removed or reordered",
+                i + 1,
+                path
+            )
+            .into());
+        }
+        if record.compute_hash() != record.hash {
+            return Err(format!("Record {} of {} was modified", i + 1, path).into());
+        }
+
+        head = Head {
+            count: head.count + 1,
+            hash: record.hash,
+        };
+    }
+    Ok(head)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn page(log: &AuditLog, event: &str, page: u64) -> Result<AuditPage, ErrorMessage> {
+        let query = AuditQuery {
+            event: Some(event.to_string()),
+            page,
+            ..Default::default()
+        };
+        log.query(&query).unwrap()
+    }
+
+    #[test]
+    fn pages_past_the_last_are_rejected() {
+        let dir = tempfile::tempdir().unwrap();
+        let log = AuditLog::open(dir.path().join("audit.log").to_str().unwrap()).unwrap();
+        for _ in 0..PAGE_SIZE + 1 {
+            log.record("alice", AuditEvent::LoginSuccess, None, String::new())
+                .unwrap();
+        }
+
+        let last = page(&log, "login_success", 1).unwrap();
+        assert_eq!((last.entries.len(), last.pages), (1, 2));
+        for p in [2, u64::MAX / PAGE_SIZE + 1, u64::MAX] {
+            assert!(matches!(
+                page(&log, "login_success", p),
+                Err(ErrorMessage::ErrorInvalidFilter)
+            ));
+        }
+
+        // Nothing matches, the first page is empty
+        let empty = page(&log, "logout", 0).unwrap();
+        assert_eq!((empty.entries.len(), empty.pages), (0, 0));
+        assert!(page(&log, "logout", 1).is_err());
+    }
+}
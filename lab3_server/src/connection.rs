@@ -2,15 +2,54 @@ use native_tls::TlsStream;
 use serde::de::DeserializeOwned;
 use serde::Serialize;
 use std::error::Error;
-use std::net::TcpStream;
+use std::io::{self, Read, Write};
+use std::net::{IpAddr, TcpStream};
+use std::time::Duration;
+
+/// What the messages go through, TLS on top of TCP except in the tests
+trait Stream: Read + Write + Send {
+    fn tcp(&self) -> &TcpStream;
+}
+
+impl Stream for TlsStream<TcpStream> {
+    fn tcp(&self) -> &TcpStream {
+        self.get_ref()
+    }
+}
+
+#[cfg(test)]
+impl Stream for TcpStream {
+    fn tcp(&self) -> &TcpStream {
+        self
+    }
+}
 
 pub struct Connection {
-    stream: TlsStream<TcpStream>,
+    stream: Box<dyn Stream>,
 }
 
 impl Connection {
     pub fn new(stream: TlsStream<TcpStream>) -> Connection {
-        Connection { stream }
+        Connection {
+            stream: Box::new(stream),
+        }
+    }
+
+    /// A connection without TLS, so the tests need no certificate
+    #[cfg(test)]
+    pub fn plain(stream: TcpStream) -> Connection {
+        Connection {
+            stream: Box::new(stream),
+        }
+    }
+
+    /// Makes `receive` fail if nothing is received for this long
+    pub fn set_timeout(&self, timeout: Duration) -> Result<(), Box<dyn Error>> {
+        Ok(self.stream.tcp().set_read_timeout(Some(timeout))?)
+    }
+
+    pub fn peer_ip(&self) -> Result<IpAddr, Box<dyn Error>> {
+        Ok(self.stream.tcp().peer_addr()?.ip())
     }
 
     pub fn send<T>(&mut self, o: &T) -> Result<(), Box<dyn Error>>
@@ -27,3 +66,16 @@ impl Connection {
         Ok(bincode::deserialize_from(&mut self.stream)?)
     }
 }
+
+/// Whether `receive` failed because of the timeout
+pub fn is_timeout(e: &(dyn Error + 'static)) -> bool {
+    match e.downcast_ref::<bincode::Error>().map(|e| e.as_ref()) {
+        Some(bincode::ErrorKind::Io(e)) => {
+            matches!(
+                e.kind(),
+                io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+            )
+        }
+        _ => false,
+    }
+}
@@ -0,0 +1,109 @@
+/// This file is used to choose the accounts a new store starts with
+///
+/// On the first run, the operator gives the initial HR account with
+/// `LAB3_INITIAL_HR_USERNAME`, `LAB3_INITIAL_HR_PASSWORD` and
+/// `LAB3_INITIAL_HR_PHONE`. Without them, the store starts with the well-known
+/// default accounts, which is only allowed outside of production mode
+/// (`LAB3_PRODUCTION=1`). In production mode the server also refuses to start
+/// while a default account still has its default password.
+use super::UserStore;
+use crate::password;
+use crate::user::{UserAccount, UserRole};
+use std::env;
+use std::error::Error;
+use validation::Validator;
+
+const DEFAULT_PASSWORD: &str = "def4Ult*pass";
+const DEFAULT_ACCOUNTS: [(&str, &str, UserRole); 2] = [
+    ("default_user", "078-453-9872", UserRole::StandardUser),
+    ("default_hr", "079-317-5289", UserRole::HR),
+];
+
+pub fn is_production() -> bool {
+    matches!(env::var("LAB3_PRODUCTION").as_deref(), Ok("1") | Ok("true"))
+}
+
+fn required_var(name: &str) -> Result<String, Box<dyn Error>> {
+    env::var(name).map_err(|_| {
+        format!(
+            "{} must be set along with the other initial HR variables",
+            name
+        )
+        .into()
+    })
+}
+
+/// The initial HR account from the environment, if it is given
+fn initial_hr() -> Result<Option<UserAccount>, Box<dyn Error>> {
+    let username = match env::var("LAB3_INITIAL_HR_USERNAME") {
+        Ok(username) => username,
+        Err(_) => return Ok(None),
+    };
+    let password = required_var("LAB3_INITIAL_HR_PASSWORD")?;
+    let phone = required_var("LAB3_INITIAL_HR_PHONE")?;
+
+    Validator::validate_username(&username)?;
+    Validator::validate_password(&password)?;
+    Validator::validate_phone_number(&phone)?;
+    if password == DEFAULT_PASSWORD {
+        return Err("The initial HR password cannot be the default password".into());
+    }
+
+    Ok(Some(UserAccount::new(
+        username,
+        password,
+        phone,
+        UserRole::HR,
+    )?))
+}
+
+/// Accounts an empty store starts with
+pub fn initial_users() -> Result<Vec<UserAccount>, Box<dyn Error>> {
+    if let Some(hr) = initial_hr()? {
+        info!("Creating the initial HR account {}", hr.username());
+        return Ok(vec![hr]);
+    }
+
+    if is_production() {
+        return Err(
+            "No initial HR account given, set LAB3_INITIAL_HR_USERNAME, \
+            LAB3_INITIAL_HR_PASSWORD and LAB3_INITIAL_HR_PHONE"
+                .into(),
+        );
+    }
+
+    warn!("No initial HR account given, creating the default accounts");
+    DEFAULT_ACCOUNTS
+        .iter()
+        .map(|(username, phone, role)| {
+            UserAccount::new(
+                username.to_string(),
+                DEFAULT_PASSWORD.to_string(),
+                phone.to_string(),
+                *role,
+            )
+        })
+        .collect()
+}
+
+/// Fails if a default account can still be used with the default password
+pub fn check_default_credentials(store: &dyn UserStore) -> Result<(), Box<dyn Error>> {
+    let mut found = Vec::new();
+    for (username, _, _) in DEFAULT_ACCOUNTS {
+        if let Some(user) = store.get(username)? {
+            if password::verify(user.password_hash(), DEFAULT_PASSWORD) {
+                found.push(username);
+            }
+        }
+    }
+
+    if found.is_empty() {
+        Ok(())
+    } else {
+        Err(format!(
+            "The default password is still set for {}, change it before running in production mode",
+            found.join(", ")
+        )
+        .into())
+    }
+}
@@ -0,0 +1,228 @@
+/// This file is used to encrypt the RON database at rest
+///
+/// The file is sealed with XChaCha20-Poly1305 and laid out as
+///   magic (8 bytes) | format version (1) | key ID (8) | nonce (24) | ciphertext
+/// where the key ID is the start of the SHA-256 of the key, so a file can be
+/// matched with its key. The header is authenticated along with the data.
+///
+/// The key is 32 bytes written as hex, read from the file named by
+/// `LAB3_DB_KEY_FILE` or from `LAB3_DB_KEY`. Without a key the file is written
+/// in plain text. A plain text file is refused when a key is set, or anyone
+/// able to write the file could replace it; it is encrypted once with
+/// `lab3_server rotate-key`, run without a key set.
+use crate::fs::write_atomic;
+use anyhow::anyhow;
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, OsRng, Payload};
+use chacha20poly1305::{XChaCha20Poly1305, XNonce};
+use rustbreak::backend::Backend;
+use rustbreak::error::BackendResult;
+use sha2::{Digest, Sha256};
+use std::env;
+use std::error::Error;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+
+const MAGIC: &[u8; 8] = b"RESIGNDB";
+const FORMAT_VERSION: u8 = 1;
+const KEY_SIZE: usize = 32;
+const KEY_ID_SIZE: usize = 8;
+const NONCE_SIZE: usize = 24;
+const HEADER_SIZE: usize = MAGIC.len() + 1 + KEY_ID_SIZE;
+
+pub struct Key {
+    id: [u8; KEY_ID_SIZE],
+    cipher: XChaCha20Poly1305,
+}
+
+impl Key {
+    fn new(bytes: &[u8]) -> Result<Self, Box<dyn Error>> {
+        if bytes.len() != KEY_SIZE {
+            return Err(format!("The database key must be {} bytes long", KEY_SIZE).into());
+        }
+
+        let mut id = [0u8; KEY_ID_SIZE];
+        id.copy_from_slice(&Sha256::digest(bytes)[..KEY_ID_SIZE]);
+        Ok(Self {
+            id,
+            cipher: XChaCha20Poly1305::new_from_slice(bytes)?,
+        })
+    }
+
+    pub fn from_hex(key: &str) -> Result<Self, Box<dyn Error>> {
+        Self::new(&hex::decode(key.trim())?)
+    }
+
+    /// Reads the key from `LAB3_DB_KEY_FILE` or `LAB3_DB_KEY`, if any
+    pub fn from_env() -> Result<Option<Self>, Box<dyn Error>> {
+        if let Ok(path) = env::var("LAB3_DB_KEY_FILE") {
+            let key = fs::read_to_string(&path)
+                .map_err(|e| format!("Could not read the key file {}: {}", path, e))?;
+            Ok(Some(Self::from_hex(&key)?))
+        } else if let Ok(key) = env::var("LAB3_DB_KEY") {
+            Ok(Some(Self::from_hex(&key)?))
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// Generates a new key and writes it to a new file only readable by its
+    /// owner
+    pub fn generate(path: &Path) -> Result<Self, Box<dyn Error>> {
+        let key = XChaCha20Poly1305::generate_key(&mut OsRng);
+
+        let mut file = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(0o600)
+            .open(path)
+            .map_err(|e| format!("Could not create the key file {}: {}", path.display(), e))?;
+        file.write_all(hex::encode(key).as_bytes())?;
+        file.sync_all()?;
+
+        Self::new(&key)
+    }
+
+    pub fn id(&self) -> String {
+        hex::encode(self.id)
+    }
+
+    fn header(&self) -> Vec<u8> {
+        let mut header = MAGIC.to_vec();
+        header.push(FORMAT_VERSION);
+        header.extend_from_slice(&self.id);
+        header
+    }
+
+    fn seal(&self, plaintext: &[u8]) -> BackendResult<Vec<u8>> {
+        let header = self.header();
+        let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let payload = Payload {
+            msg: plaintext,
+            aad: &header,
+        };
+        let ciphertext = self
+            .cipher
+            .encrypt(&nonce, payload)
+            .map_err(|_| anyhow!("Could not encrypt the database"))?;
+
+        let mut data = header;
+        data.extend_from_slice(&nonce);
+        data.extend_from_slice(&ciphertext);
+        Ok(data)
+    }
+
+    fn open(&self, data: &[u8]) -> BackendResult<Vec<u8>> {
+        if data.len() < HEADER_SIZE + NONCE_SIZE || data[MAGIC.len()] != FORMAT_VERSION {
+            return Err(anyhow!("Unsupported encrypted database format").into());
+        }
+
+        let (header, rest) = data.split_at(HEADER_SIZE);
+        let id = &header[MAGIC.len() + 1..];
+        if id != self.id {
+            return Err(anyhow!(
+                "The database is encrypted with key {}, not with key {}",
+                hex::encode(id),
+                self.id()
+            )
+            .into());
+        }
+
+        let (nonce, ciphertext) = rest.split_at(NONCE_SIZE);
+        let payload = Payload {
+            msg: ciphertext,
+            aad: header,
+        };
+        let plaintext = self
+            .cipher
+            .decrypt(XNonce::from_slice(nonce), payload)
+            .map_err(|_| anyhow!("The database could not be decrypted, it was modified"))?;
+        Ok(plaintext)
+    }
+}
+
+fn is_encrypted(data: &[u8]) -> bool {
+    data.starts_with(MAGIC)
+}
+
+/// rustbreak backend reading and writing a file, encrypted if there is a key
+pub struct EncryptedFileBackend {
+    path: PathBuf,
+    key: Option<Key>,
+}
+
+impl EncryptedFileBackend {
+    pub fn new(path: &Path, key: Option<Key>) -> Self {
+        Self {
+            path: path.to_path_buf(),
+            key,
+        }
+    }
+}
+
+impl Backend for EncryptedFileBackend {
+    fn get_data(&mut self) -> BackendResult<Vec<u8>> {
+        let data = fs::read(&self.path)?;
+        match (&self.key, is_encrypted(&data)) {
+            (Some(key), true) => key.open(&data),
+            (None, true) => {
+                Err(anyhow!("{} is encrypted but no key is set", self.path.display()).into())
+            }
+            (Some(_), false) => Err(anyhow!(
+                "{} is not encrypted but a key is set, encrypt it with \
+                `lab3_server rotate-key <new key file>` without a key set",
+                self.path.display()
+            )
+            .into()),
+            (None, false) => Ok(data),
+        }
+    }
+
+    fn put_data(&mut self, data: &[u8]) -> BackendResult<()> {
+        match &self.key {
+            Some(key) => write_atomic(&self.path, &key.seal(data)?)?,
+            None => write_atomic(&self.path, data)?,
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key() -> Key {
+        Key::new(&[7u8; KEY_SIZE]).unwrap()
+    }
+
+    #[test]
+    fn plain_text_is_refused_with_a_key() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("db.ron");
+        fs::write(&path, b"(data: {})").unwrap();
+
+        assert!(EncryptedFileBackend::new(&path, Some(key()))
+            .get_data()
+            .is_err());
+        assert!(EncryptedFileBackend::new(&path, None).get_data().is_ok());
+    }
+
+    #[test]
+    fn encrypted_data_is_read_back() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("db.ron");
+
+        EncryptedFileBackend::new(&path, Some(key()))
+            .put_data(b"(data: {})")
+            .unwrap();
+        assert!(!fs::read(&path).unwrap().starts_with(b"(data"));
+        assert_eq!(
+            EncryptedFileBackend::new(&path, Some(key()))
+                .get_data()
+                .unwrap(),
+            b"(data: {})"
+        );
+        assert!(EncryptedFileBackend::new(&path, None).get_data().is_err());
+    }
+}
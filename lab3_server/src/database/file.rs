@@ -0,0 +1,266 @@
+/// This file is used to store the user accounts in a RON file with rustbreak,
+/// encrypted if a key is set (see `encryption.rs`)
+use super::encryption::{EncryptedFileBackend, Key};
+use super::{initial_users, UserStore};
+use crate::fs::temporary_path;
+use crate::user::UserAccount;
+use rustbreak::{deser::Ron, RustbreakError};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+struct Database {
+    data: HashMap<String, UserAccount>,
+}
+
+impl Database {
+    fn empty() -> Self {
+        Database {
+            data: HashMap::new(),
+        }
+    }
+
+    /// Checks what the format alone does not guarantee
+    fn check(&self) -> Result<(), String> {
+        for (username, user) in &self.data {
+            if username != user.username() {
+                return Err(format!(
+                    "the entry {} holds the account of {}",
+                    username,
+                    user.username()
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    fn with_users(users: Vec<UserAccount>) -> Self {
+        let data = users
+            .into_iter()
+            .map(|u| (u.username().to_string(), u))
+            .collect();
+        debug!("Database initialized");
+        Database { data }
+    }
+}
+
+type RonDatabase = rustbreak::Database<Database, EncryptedFileBackend, Ron>;
+
+pub struct FileStore {
+    path: PathBuf,
+    db: RonDatabase,
+    // Held from a change until it is saved, so a change that could not be
+    // saved is undone without undoing another one
+    writing: Mutex<()>,
+}
+
+/// rustbreak errors only tell their kind, the details are in their sources
+fn with_cause(e: RustbreakError) -> Box<dyn Error> {
+    let mut message = e.to_string();
+    let mut source = e.source();
+    while let Some(s) = source {
+        message.push_str(&format!(": {}", s));
+        source = s.source();
+    }
+    message.into()
+}
+
+impl FileStore {
+    /// Opens the file, or creates it with the initial accounts. A file that
+    /// cannot be read is an error, it is never replaced by the defaults.
+    pub fn open(path: &str, key: Option<Key>) -> Result<Self, Box<dyn Error>> {
+        // Left by a write that did not complete, the file itself is intact
+        let tmp = temporary_path(Path::new(path));
+        if tmp.exists() {
+            warn!("Removing {} left by an interrupted write", tmp.display());
+            fs::remove_file(&tmp)?;
+        }
+
+        if Path::new(path).exists() {
+            Self::load(path, key)
+        } else {
+            let store = Self::new(path, key);
+            store
+                .db
+                .put_data(Database::with_users(initial_users()?), true)
+                .map_err(with_cause)?;
+            Ok(store)
+        }
+    }
+
+    /// Opens an existing file, without creating the initial accounts
+    pub fn load(path: &str, key: Option<Key>) -> Result<Self, Box<dyn Error>> {
+        if !Path::new(path).exists() {
+            return Err(format!("{} not found", path).into());
+        }
+        let store = Self::new(path, key);
+        store.db.load().map_err(with_cause)?;
+        store
+            .db
+            .read(Database::check)?
+            .map_err(|e| format!("{} is inconsistent: {}", path, e))?;
+        Ok(store)
+    }
+
+    fn new(path: &str, key: Option<Key>) -> Self {
+        let path = PathBuf::from(path);
+        let backend = EncryptedFileBackend::new(&path, key);
+        Self {
+            db: RonDatabase::from_parts(Database::empty(), backend, Ron),
+            path,
+            writing: Mutex::new(()),
+        }
+    }
+
+    /// Saves the file again with another key, or in plain text without one
+    pub fn rekey(self, key: Option<Key>) -> Result<(), Box<dyn Error>> {
+        let (data, _, deser) = self.db.into_inner().map_err(with_cause)?;
+        let backend = EncryptedFileBackend::new(&self.path, key);
+        RonDatabase::from_parts(data, backend, deser)
+            .save()
+            .map_err(with_cause)
+    }
+
+    /// Applies the change and saves the file if it changed anything. Should
+    /// saving fail, the change is undone, so the accounts in memory are always
+    /// the ones of the file.
+    fn write<F>(&self, f: F) -> Result<bool, Box<dyn Error>>
+    where
+        F: FnOnce(&mut HashMap<String, UserAccount>) -> bool,
+    {
+        let _writing = self.writing.lock().unwrap();
+        let previous = self.db.get_data(false)?;
+        if !self.db.write(|db| f(&mut db.data))? {
+            return Ok(false);
+        }
+        if let Err(e) = self.db.save() {
+            self.db.put_data(previous, false)?;
+            return Err(with_cause(e));
+        }
+        Ok(true)
+    }
+}
+
+impl UserStore for FileStore {
+    fn get(&self, username: &str) -> Result<Option<UserAccount>, Box<dyn Error>> {
+        Ok(self.db.borrow_data()?.data.get(username).cloned())
+    }
+
+    fn insert(&self, user: &UserAccount) -> Result<bool, Box<dyn Error>> {
+        self.write(|data| {
+            if data.contains_key(user.username()) {
+                false
+            } else {
+                data.insert(user.username().to_string(), user.clone());
+                true
+            }
+        })
+    }
+
+    fn update(&self, user: &UserAccount) -> Result<bool, Box<dyn Error>> {
+        self.write(|data| match data.get_mut(user.username()) {
+            Some(u) if u.version() == user.version() => {
+                *u = user.next_version();
+                true
+            }
+            _ => false,
+        })
+    }
+
+    fn delete(&self, username: &str) -> Result<bool, Box<dyn Error>> {
+        self.write(|data| data.remove(username).is_some())
+    }
+
+    fn list(&self) -> Result<Vec<UserAccount>, Box<dyn Error>> {
+        Ok(self.db.borrow_data()?.data.values().cloned().collect())
+    }
+
+    fn compare_and_swap(
+        &self,
+        expected: &UserAccount,
+        new: &UserAccount,
+    ) -> Result<bool, Box<dyn Error>> {
+        self.write(|data| match data.get_mut(expected.username()) {
+            Some(u) if u == expected => {
+                *u = new.next_version();
+                true
+            }
+            _ => false,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::account;
+    use crate::user::UserRole;
+
+    /// Creates the file with the initial accounts, returns its content
+    fn create(path: &str) -> String {
+        FileStore::open(path, None).unwrap();
+        fs::read_to_string(path).unwrap()
+    }
+
+    #[test]
+    fn leftover_temporary_file_is_removed() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("db.ron");
+        let path = path.to_str().unwrap();
+        let content = create(path);
+
+        // The server stopped while writing the next version
+        let tmp = temporary_path(Path::new(path));
+        fs::write(&tmp, &content[..content.len() / 2]).unwrap();
+
+        let store = FileStore::open(path, None).unwrap();
+        assert!(!tmp.exists());
+        assert!(!store.list().unwrap().is_empty());
+        assert_eq!(fs::read_to_string(path).unwrap(), content);
+    }
+
+    #[test]
+    fn corrupt_file_is_not_replaced() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("db.ron");
+        let path = path.to_str().unwrap();
+        let content = create(path);
+
+        let truncated = &content[..content.len() / 2];
+        fs::write(path, truncated).unwrap();
+
+        assert!(FileStore::open(path, None).is_err());
+        assert_eq!(fs::read_to_string(path).unwrap(), truncated);
+    }
+
+    #[test]
+    fn failed_save_is_undone() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("db.ron");
+        let path = path.to_str().unwrap();
+        let content = create(path);
+        let store = FileStore::open(path, None).unwrap();
+        let before = store.list().unwrap();
+
+        // The file cannot be written while its temporary file is a directory
+        let tmp = temporary_path(Path::new(path));
+        fs::create_dir(&tmp).unwrap();
+        let alice = account("alice", UserRole::StandardUser);
+        assert!(store.insert(&alice).is_err());
+        assert!(store.get("alice").unwrap().is_none());
+        assert_eq!(store.list().unwrap().len(), before.len());
+        assert_eq!(fs::read_to_string(path).unwrap(), content);
+
+        // Nothing is left to be saved along with the next change
+        fs::remove_dir(&tmp).unwrap();
+        let bob = account("bob", UserRole::StandardUser);
+        assert!(store.insert(&bob).unwrap());
+        let saved = FileStore::load(path, None).unwrap();
+        assert!(saved.get("alice").unwrap().is_none());
+        assert!(saved.get("bob").unwrap().is_some());
+    }
+}
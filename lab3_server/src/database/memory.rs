@@ -0,0 +1,71 @@
+/// This file is used to keep the user accounts in memory, e.g. for tests or
+/// demonstrations that must not touch `db.ron`
+use super::UserStore;
+use crate::user::UserAccount;
+use std::collections::HashMap;
+use std::error::Error;
+use std::sync::Mutex;
+
+pub struct MemoryStore {
+    data: Mutex<HashMap<String, UserAccount>>,
+}
+
+impl MemoryStore {
+    pub fn new(users: Vec<UserAccount>) -> Self {
+        let data = users
+            .into_iter()
+            .map(|u| (u.username().to_string(), u))
+            .collect();
+        Self {
+            data: Mutex::new(data),
+        }
+    }
+}
+
+impl UserStore for MemoryStore {
+    fn get(&self, username: &str) -> Result<Option<UserAccount>, Box<dyn Error>> {
+        Ok(self.data.lock().unwrap().get(username).cloned())
+    }
+
+    fn insert(&self, user: &UserAccount) -> Result<bool, Box<dyn Error>> {
+        let mut data = self.data.lock().unwrap();
+        if data.contains_key(user.username()) {
+            Ok(false)
+        } else {
+            data.insert(user.username().to_string(), user.clone());
+            Ok(true)
+        }
+    }
+
+    fn update(&self, user: &UserAccount) -> Result<bool, Box<dyn Error>> {
+        match self.data.lock().unwrap().get_mut(user.username()) {
+            Some(u) if u.version() == user.version() => {
+                *u = user.next_version();
+                Ok(true)
+            }
+            _ => Ok(false),
+        }
+    }
+
+    fn delete(&self, username: &str) -> Result<bool, Box<dyn Error>> {
+        Ok(self.data.lock().unwrap().remove(username).is_some())
+    }
+
+    fn list(&self) -> Result<Vec<UserAccount>, Box<dyn Error>> {
+        Ok(self.data.lock().unwrap().values().cloned().collect())
+    }
+
+    fn compare_and_swap(
+        &self,
+        expected: &UserAccount,
+        new: &UserAccount,
+    ) -> Result<bool, Box<dyn Error>> {
+        match self.data.lock().unwrap().get_mut(expected.username()) {
+            Some(u) if u == expected => {
+                *u = new.next_version();
+                Ok(true)
+            }
+            _ => Ok(false),
+        }
+    }
+}
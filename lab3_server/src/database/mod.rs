@@ -0,0 +1,242 @@
+/// This file is used to store and retrieve user accounts from the database
+///
+/// The accounts are accessed through the `UserStore` trait so the storage can
+/// be chosen when the server starts, with `LAB3_USER_STORE`:
+///   file    the RON file `db.ron` in the working directory (default)
+///   sqlite  the SQLite database `db.sqlite` in the working directory
+///   memory  kept in memory only, lost when the server stops
+///
+/// The accounts a new store starts with are described in `bootstrap.rs`.
+///
+/// An existing `db.ron` can be copied to the SQLite database with
+/// `lab3_server import-ron [path]`, and `db.ron` can be encrypted with a new
+/// key with `lab3_server rotate-key <new key file>`.
+///
+/// Only `db.ron` is encrypted. The SQLite database holds the password hashes
+/// and TOTP secrets in plain text, so it refuses to start with a key set
+/// rather than let the key suggest otherwise.
+///
+/// Tasks todo: - Log stuff whenever required
+///             - Potential improvements
+mod bootstrap;
+mod encryption;
+mod file;
+mod memory;
+mod sqlite;
+
+use crate::user::UserAccount;
+use std::env;
+use std::error::Error;
+use std::fs;
+use std::path::Path;
+use std::sync::Arc;
+
+pub use bootstrap::initial_users;
+pub use encryption::Key;
+pub use file::FileStore;
+pub use memory::MemoryStore;
+pub use sqlite::SqliteStore;
+
+pub static DB_PATH: &str = "db.ron";
+static SQLITE_PATH: &str = "db.sqlite";
+
+pub trait UserStore: Send + Sync {
+    fn get(&self, username: &str) -> Result<Option<UserAccount>, Box<dyn Error>>;
+
+    /// Adds a new account, returns false if the username is already taken
+    fn insert(&self, user: &UserAccount) -> Result<bool, Box<dyn Error>>;
+
+    /// Replaces an account that was not changed since it was read, i.e. whose
+    /// stored version is still the one of `user`. Returns false if it was
+    /// changed or removed in the meantime.
+    fn update(&self, user: &UserAccount) -> Result<bool, Box<dyn Error>>;
+
+    /// Removes an account, returns false if there was none
+    fn delete(&self, username: &str) -> Result<bool, Box<dyn Error>>;
+
+    fn list(&self) -> Result<Vec<UserAccount>, Box<dyn Error>>;
+
+    /// Replaces the account by `new`, a modified copy of `expected`, only if it
+    /// is still equal to `expected`. Returns false if it was changed in the
+    /// meantime.
+    fn compare_and_swap(
+        &self,
+        expected: &UserAccount,
+        new: &UserAccount,
+    ) -> Result<bool, Box<dyn Error>>;
+}
+
+/// Opens the store selected by `LAB3_USER_STORE`
+pub fn open_from_env() -> Result<Arc<dyn UserStore>, Box<dyn Error>> {
+    let store: Arc<dyn UserStore> = match env::var("LAB3_USER_STORE").as_deref() {
+        Ok("file") | Err(_) => {
+            let key = Key::from_env()?;
+            match &key {
+                Some(key) => info!("{} is encrypted with key {}", DB_PATH, key.id()),
+                None => warn!("No database key set, {} is not encrypted", DB_PATH),
+            }
+            Arc::new(FileStore::open(DB_PATH, key)?)
+        }
+        Ok("sqlite") => {
+            if Key::from_env()?.is_some() {
+                return Err(format!(
+                    "{} cannot be encrypted, unset LAB3_DB_KEY and LAB3_DB_KEY_FILE",
+                    SQLITE_PATH
+                )
+                .into());
+            }
+            let store = SqliteStore::open(SQLITE_PATH)?;
+            if store.list()?.is_empty() {
+                info!("Empty database, adding the initial accounts");
+                for user in initial_users()? {
+                    store.insert(&user)?;
+                }
+            }
+            Arc::new(store)
+        }
+        Ok("memory") => {
+            warn!("Using an in-memory user store, changes will be lost on exit");
+            Arc::new(MemoryStore::new(initial_users()?))
+        }
+        Ok(other) => return Err(format!("Unknown user store {}", other).into()),
+    };
+
+    if bootstrap::is_production() {
+        bootstrap::check_default_credentials(store.as_ref())?;
+    }
+    Ok(store)
+}
+
+/// Copies the accounts of a RON database to the SQLite database. Accounts that
+/// already exist there are left untouched.
+pub fn import_ron(path: &str) -> Result<(), Box<dyn Error>> {
+    let from = FileStore::load(path, Key::from_env()?)?;
+    let to = SqliteStore::open(SQLITE_PATH)?;
+
+    let (mut imported, mut skipped) = (0, 0);
+    for user in from.list()? {
+        if to.insert(&user)? {
+            imported += 1;
+        } else {
+            warn!("User {} already exists, skipped", user.username());
+            skipped += 1;
+        }
+    }
+
+    info!(
+        "Imported {} account(s) from {} to {}, {} skipped",
+        imported, path, SQLITE_PATH, skipped
+    );
+    Ok(())
+}
+
+/// Generates a new key in a new file and encrypts `db.ron` with it. The
+/// current key, if any, is the one set in the environment.
+pub fn rotate_key(key_path: &str) -> Result<(), Box<dyn Error>> {
+    let store = FileStore::load(DB_PATH, Key::from_env()?)?;
+
+    // The key is saved before it is used, so the data is never encrypted with
+    // a lost key
+    let key = Key::generate(Path::new(key_path))?;
+    let id = key.id();
+    if let Err(e) = store.rekey(Some(key)) {
+        // Removed so the rotation can be tried again, unless the file was
+        // encrypted with it all the same
+        if FileStore::load(DB_PATH, Key::from_env()?).is_ok() {
+            fs::remove_file(key_path)?;
+        }
+        return Err(e);
+    }
+
+    info!(
+        "{} encrypted with key {}, set LAB3_DB_KEY_FILE to {}",
+        DB_PATH, id, key_path
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::user::UserRole;
+
+    fn with_phone(user: &UserAccount, phone_number: &str) -> UserAccount {
+        let mut user = user.clone();
+        user.phone_number = phone_number.to_string();
+        user
+    }
+
+    /// Checks the behaviour every store must have
+    fn check_contract(store: &dyn UserStore) {
+        let alice = UserAccount::new(
+            "alice".to_string(),
+            "Secr3t*pass".to_string(),
+            "079-111-0000".to_string(),
+            UserRole::StandardUser,
+        )
+        .unwrap();
+
+        assert!(store.insert(&alice).unwrap());
+        assert!(!store.insert(&with_phone(&alice, "079-111-9999")).unwrap());
+        assert_eq!(
+            store.get("alice").unwrap().unwrap().phone_number,
+            "079-111-0000"
+        );
+
+        // Two writers read the same version, only the first one succeeds
+        let read = store.get("alice").unwrap().unwrap();
+        assert!(store.update(&with_phone(&read, "079-111-1111")).unwrap());
+        assert!(!store.update(&with_phone(&read, "079-111-2222")).unwrap());
+        let current = store.get("alice").unwrap().unwrap();
+        assert_eq!(current.phone_number, "079-111-1111");
+        assert_eq!(current.version(), read.version() + 1);
+
+        // Only swapped while the account is exactly the expected one
+        let swapped = with_phone(&current, "079-111-3333");
+        assert!(store.compare_and_swap(&current, &swapped).unwrap());
+        assert!(!store
+            .compare_and_swap(&current, &with_phone(&current, "079-111-4444"))
+            .unwrap());
+        assert_eq!(
+            store.get("alice").unwrap().unwrap().phone_number,
+            "079-111-3333"
+        );
+
+        assert!(store
+            .list()
+            .unwrap()
+            .iter()
+            .any(|u| u.username() == "alice"));
+
+        let last = store.get("alice").unwrap().unwrap();
+        assert!(store.delete("alice").unwrap());
+        assert!(!store.delete("alice").unwrap());
+        assert!(store.get("alice").unwrap().is_none());
+        assert!(!store.update(&last).unwrap());
+        assert!(!store.compare_and_swap(&last, &last).unwrap());
+    }
+
+    #[test]
+    fn memory_store() {
+        check_contract(&MemoryStore::new(Vec::new()));
+    }
+
+    #[test]
+    fn file_store() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("db.ron");
+        let path = path.to_str().unwrap();
+
+        check_contract(&FileStore::open(path, None).unwrap());
+
+        // Every change was saved
+        let store = FileStore::load(path, None).unwrap();
+        assert!(store.get("alice").unwrap().is_none());
+        assert!(!store.list().unwrap().is_empty());
+    }
+
+    #[test]
+    fn sqlite_store() {
+        check_contract(&SqliteStore::open(":memory:").unwrap());
+    }
+}
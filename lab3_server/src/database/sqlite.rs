@@ -0,0 +1,325 @@
+/// This file is used to store the user accounts in an SQLite database
+///
+/// The schema is created and upgraded by the migrations below. Its version is
+/// kept in `PRAGMA user_version` so each migration runs only once: new
+/// migrations must be appended, existing ones never edited.
+use super::UserStore;
+use crate::user::{AccountField, AccountRecord, Revision, UserAccount, UserRole};
+use rusqlite::{params, Connection, OptionalExtension};
+use std::error::Error;
+use std::str::FromStr;
+use std::sync::Mutex;
+
+static MIGRATIONS: &[&str] = &[
+    // 1: accounts
+    "CREATE TABLE users (
+        id INTEGER PRIMARY KEY,
+        username TEXT NOT NULL UNIQUE,
+        password_hash TEXT NOT NULL,
+        phone_number TEXT NOT NULL,
+        role TEXT NOT NULL
+    );
+    CREATE INDEX users_phone_number ON users (phone_number);",
+    // 2: two-factor authentication and password changes
+    "ALTER TABLE users ADD COLUMN totp_secret TEXT;
+    ALTER TABLE users ADD COLUMN must_change_password INTEGER NOT NULL DEFAULT 0;
+    CREATE TABLE recovery_codes (
+        user_id INTEGER NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+        code_hash TEXT NOT NULL
+    );
+    CREATE INDEX recovery_codes_user_id ON recovery_codes (user_id);
+    CREATE TABLE password_history (
+        user_id INTEGER NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+        position INTEGER NOT NULL,
+        password_hash TEXT NOT NULL,
+        PRIMARY KEY (user_id, position)
+    );",
+    // 3: optimistic concurrency
+    "ALTER TABLE users ADD COLUMN version INTEGER NOT NULL DEFAULT 0;",
+    // 4: deactivated accounts
+    "ALTER TABLE users ADD COLUMN deactivated INTEGER NOT NULL DEFAULT 0;",
+    // 5: change history
+    "CREATE TABLE revisions (
+        user_id INTEGER NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+        version INTEGER NOT NULL,
+        time INTEGER NOT NULL,
+        actor TEXT NOT NULL,
+        field TEXT NOT NULL,
+        old_value TEXT NOT NULL,
+        new_value TEXT NOT NULL,
+        PRIMARY KEY (user_id, version, field)
+    );",
+    // 6: TOTP codes used once
+    "ALTER TABLE users ADD COLUMN totp_last_step INTEGER;",
+];
+
+pub struct SqliteStore {
+    conn: Mutex<Connection>,
+}
+
+impl SqliteStore {
+    /// Opens the database, creating it if needed, and applies the missing
+    /// migrations
+    pub fn open(path: &str) -> Result<Self, Box<dyn Error>> {
+        let mut conn = Connection::open(path)?;
+        conn.pragma_update(None, "foreign_keys", true)?;
+        check_integrity(&conn)?;
+        migrate(&mut conn)?;
+        Ok(Self {
+            conn: Mutex::new(conn),
+        })
+    }
+}
+
+fn check_integrity(conn: &Connection) -> Result<(), Box<dyn Error>> {
+    let result: String = conn.pragma_query_value(None, "integrity_check", |r| r.get(0))?;
+    if result == "ok" {
+        Ok(())
+    } else {
+        Err(format!("The database is corrupt: {}", result).into())
+    }
+}
+
+fn migrate(conn: &mut Connection) -> Result<(), Box<dyn Error>> {
+    let version: usize = conn.pragma_query_value(None, "user_version", |r| r.get(0))?;
+    if version > MIGRATIONS.len() {
+        return Err(format!(
+            "Database schema version {} is newer than this server supports",
+            version
+        )
+        .into());
+    }
+
+    for (i, migration) in MIGRATIONS.iter().enumerate().skip(version) {
+        let tx = conn.transaction()?;
+        tx.execute_batch(migration)?;
+        tx.pragma_update(None, "user_version", i + 1)?;
+        tx.commit()?;
+        info!("Database migrated to version {}", i + 1);
+    }
+    Ok(())
+}
+
+fn read(conn: &Connection, username: &str) -> Result<Option<UserAccount>, Box<dyn Error>> {
+    let row = conn
+        .query_row(
+            "SELECT id, password_hash, phone_number, role, totp_secret, must_change_password,
+                deactivated, version, totp_last_step
+            FROM users WHERE username = ?1",
+            [username],
+            |r| {
+                Ok((
+                    r.get::<_, i64>(0)?,
+                    r.get::<_, String>(1)?,
+                    r.get::<_, String>(2)?,
+                    r.get::<_, String>(3)?,
+                    r.get::<_, Option<String>>(4)?,
+                    r.get::<_, bool>(5)?,
+                    r.get::<_, bool>(6)?,
+                    r.get::<_, u64>(7)?,
+                    r.get::<_, Option<u64>>(8)?,
+                ))
+            },
+        )
+        .optional()?;
+
+    let (
+        id,
+        password_hash,
+        phone_number,
+        role,
+        totp_secret,
+        must_change_password,
+        deactivated,
+        version,
+        totp_last_step,
+    ) = match row {
+        Some(row) => row,
+        None => return Ok(None),
+    };
+
+    let recovery_codes = conn
+        .prepare("SELECT code_hash FROM recovery_codes WHERE user_id = ?1 ORDER BY rowid")?
+        .query_map([id], |r| r.get(0))?
+        .collect::<Result<_, _>>()?;
+    let password_history = conn
+        .prepare("SELECT password_hash FROM password_history WHERE user_id = ?1 ORDER BY position")?
+        .query_map([id], |r| r.get(0))?
+        .collect::<Result<_, _>>()?;
+    let revisions = conn
+        .prepare(
+            "SELECT version, time, actor, field, old_value, new_value FROM revisions
+            WHERE user_id = ?1 ORDER BY version, rowid",
+        )?
+        .query_map([id], |r| {
+            Ok((
+                r.get::<_, u64>(0)?,
+                r.get::<_, u64>(1)?,
+                r.get::<_, String>(2)?,
+                r.get::<_, String>(3)?,
+                r.get::<_, String>(4)?,
+                r.get::<_, String>(5)?,
+            ))
+        })?
+        .map(|row| {
+            let (version, time, actor, field, old, new) = row?;
+            Ok(Revision {
+                version,
+                time,
+                actor,
+                field: AccountField::from_str(&field)?,
+                old,
+                new,
+            })
+        })
+        .collect::<Result<_, Box<dyn Error>>>()?;
+
+    Ok(Some(
+        AccountRecord {
+            username: username.to_string(),
+            password_hash,
+            phone_number,
+            role: UserRole::from_str(&role)?,
+            totp_secret,
+            totp_last_step,
+            recovery_codes,
+            password_history,
+            must_change_password,
+            deactivated,
+            revisions,
+            version,
+        }
+        .into(),
+    ))
+}
+
+/// Inserts or replaces the account
+fn write(conn: &Connection, user: &UserAccount) -> Result<(), Box<dyn Error>> {
+    let r = AccountRecord::from(user);
+    let id: i64 = conn.query_row(
+        "INSERT INTO users
+            (username, password_hash, phone_number, role, totp_secret, must_change_password,
+            deactivated, version, totp_last_step)
+        VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)
+        ON CONFLICT (username) DO UPDATE SET
+            password_hash = excluded.password_hash,
+            phone_number = excluded.phone_number,
+            role = excluded.role,
+            totp_secret = excluded.totp_secret,
+            must_change_password = excluded.must_change_password,
+            deactivated = excluded.deactivated,
+            version = excluded.version,
+            totp_last_step = excluded.totp_last_step
+        RETURNING id",
+        params![
+            r.username,
+            r.password_hash,
+            r.phone_number,
+            r.role.to_string(),
+            r.totp_secret,
+            r.must_change_password,
+            r.deactivated,
+            r.version,
+            r.totp_last_step
+        ],
+        |row| row.get(0),
+    )?;
+
+    conn.execute("DELETE FROM recovery_codes WHERE user_id = ?1", [id])?;
+    for code in &r.recovery_codes {
+        conn.execute(
+            "INSERT INTO recovery_codes (user_id, code_hash) VALUES (?1, ?2)",
+            params![id, code],
+        )?;
+    }
+
+    conn.execute("DELETE FROM password_history WHERE user_id = ?1", [id])?;
+    for (position, hash) in r.password_history.iter().enumerate() {
+        conn.execute(
+            "INSERT INTO password_history (user_id, position, password_hash) VALUES (?1, ?2, ?3)",
+            params![id, position, hash],
+        )?;
+    }
+
+    conn.execute("DELETE FROM revisions WHERE user_id = ?1", [id])?;
+    for rev in &r.revisions {
+        conn.execute(
+            "INSERT INTO revisions (user_id, version, time, actor, field, old_value, new_value)
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+            params![
+                id,
+                rev.version,
+                rev.time,
+                rev.actor,
+                rev.field.to_string(),
+                rev.old,
+                rev.new
+            ],
+        )?;
+    }
+    Ok(())
+}
+
+impl UserStore for SqliteStore {
+    fn get(&self, username: &str) -> Result<Option<UserAccount>, Box<dyn Error>> {
+        read(&self.conn.lock().unwrap(), username)
+    }
+
+    fn insert(&self, user: &UserAccount) -> Result<bool, Box<dyn Error>> {
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction()?;
+        if read(&tx, user.username())?.is_some() {
+            return Ok(false);
+        }
+        write(&tx, user)?;
+        tx.commit()?;
+        Ok(true)
+    }
+
+    fn update(&self, user: &UserAccount) -> Result<bool, Box<dyn Error>> {
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction()?;
+        match read(&tx, user.username())? {
+            Some(u) if u.version() == user.version() => {}
+            _ => return Ok(false),
+        }
+        write(&tx, &user.next_version())?;
+        tx.commit()?;
+        Ok(true)
+    }
+
+    fn delete(&self, username: &str) -> Result<bool, Box<dyn Error>> {
+        let conn = self.conn.lock().unwrap();
+        Ok(conn.execute("DELETE FROM users WHERE username = ?1", [username])? > 0)
+    }
+
+    fn list(&self) -> Result<Vec<UserAccount>, Box<dyn Error>> {
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction()?;
+        let usernames: Vec<String> = tx
+            .prepare("SELECT username FROM users ORDER BY username")?
+            .query_map([], |r| r.get(0))?
+            .collect::<Result<_, _>>()?;
+
+        let mut users = Vec::new();
+        for username in usernames {
+            users.extend(read(&tx, &username)?);
+        }
+        Ok(users)
+    }
+
+    fn compare_and_swap(
+        &self,
+        expected: &UserAccount,
+        new: &UserAccount,
+    ) -> Result<bool, Box<dyn Error>> {
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction()?;
+        if read(&tx, expected.username())?.as_ref() != Some(expected) {
+            return Ok(false);
+        }
+        write(&tx, &new.next_version())?;
+        tx.commit()?;
+        Ok(true)
+    }
+}
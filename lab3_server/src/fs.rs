@@ -0,0 +1,31 @@
+/// This file is used to write files that must never be left truncated, such
+/// as the database and the policy
+///
+/// The data is written to a temporary file next to the final one, which is
+/// then renamed over it, so a crash while writing leaves the old file intact.
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+/// Where `write_atomic` writes the file before renaming it
+pub fn temporary_path(path: &Path) -> PathBuf {
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    PathBuf::from(tmp)
+}
+
+/// Writes the file next to its final location, syncs it, then renames it
+pub fn write_atomic(path: &Path, data: &[u8]) -> io::Result<()> {
+    let tmp = temporary_path(path);
+
+    let mut file = File::create(&tmp)?;
+    file.write_all(data)?;
+    file.sync_all()?;
+    fs::rename(&tmp, path)?;
+
+    // Make the rename itself durable
+    if let Some(dir) = path.parent().filter(|d| !d.as_os_str().is_empty()) {
+        File::open(dir)?.sync_all()?;
+    }
+    Ok(())
+}
@@ -5,21 +5,36 @@ mod access_control;
 /// Tasks todo: - Configure the TLS server properly.
 ///             - Log stuff whenever required
 mod action;
+mod audit;
 mod connection;
 mod database;
+mod fs;
+mod password;
+mod session;
+#[cfg(test)]
+mod testing;
+mod throttle;
+mod two_factor;
 mod user;
 
 use crate::access_control::AccessController;
-use crate::action::{Action, ConnectedUser};
+use crate::action::ConnectedUser;
+use crate::audit::AuditLog;
+use crate::database::UserStore;
+use crate::session::{SessionConfig, SessionManager};
+use crate::throttle::Throttle;
 use crate::user::UserRole;
 use connection::Connection;
 use lazy_static::lazy_static;
 use native_tls::{Identity, Protocol, TlsAcceptor};
+use protocol::{Request, Response};
 use rand::Rng;
+use std::env;
 use std::error::Error;
 use std::fs::File;
 use std::io::Read;
 use std::net::{TcpListener, TcpStream};
+use std::process;
 use std::sync::Arc;
 use std::thread;
 
@@ -44,7 +59,7 @@ lazy_static! {
     ];
 }
 
-// Handles client connection by sending a banner and then waiting for a client action
+// Handles client connection by sending a banner and then waiting for a client request
 fn handle_client(u: &mut ConnectedUser) -> Result<(), Box<dyn Error>> {
     loop {
         let mut banner = "Welcome to RESIGN (hR onlinE uSer dIrectory manaGemeNt)!".to_string();
@@ -58,27 +73,75 @@ fn handle_client(u: &mut ConnectedUser) -> Result<(), Box<dyn Error>> {
                     MOTIVATIONAL_QUOTES[rand::thread_rng().gen_range(0..MOTIVATIONAL_QUOTES.len())];
                 banner.push_str(format!("\nQuote of the day: {}\n", quote).as_str());
             }
+
+            if u.must_change_password()? {
+                banner.push_str("\nYour password was reset, please change it");
+            } else if u.must_enable_two_factor()? {
+                banner.push_str(
+                    "\nYour account requires two-factor authentication, please set it up",
+                );
+            }
         }
 
-        // We send the banner to  the client and we expect to receive an Action
-        u.conn().send(&banner)?;
-        let action = u.conn().receive::<Action>()?;
-        action.perform(u)?;
+        // We send the banner to  the client and we expect to receive a Request
+        u.conn().send(&Response::Ready { banner })?;
+        let request = loop {
+            match u.conn().receive::<Request>() {
+                Ok(request) => break request,
+                // Idle for too long: log the user out first, then close the
+                // connection if it stays idle
+                Err(e) if connection::is_timeout(e.as_ref()) && !u.is_anonymous() => {
+                    u.expire_session()
+                }
+                Err(e) if connection::is_timeout(e.as_ref()) => {
+                    info!("Closing idle connection");
+                    return Ok(());
+                }
+                Err(e) => return Err(e),
+            }
+        };
+
+        let exit = matches!(request, Request::Exit);
+        let response = action::perform(u, request)?;
+        u.conn().send(&response)?;
+        if exit {
+            return Ok(());
+        }
     }
 }
 
-fn accept(stream: TcpStream, access_control: Arc<AccessController>, acceptor: Arc<TlsAcceptor>) {
+fn accept(
+    stream: TcpStream,
+    store: Arc<dyn UserStore>,
+    access_control: Arc<AccessController>,
+    throttle: Arc<Throttle>,
+    sessions: Arc<SessionManager>,
+    audit_log: Arc<AuditLog>,
+    acceptor: Arc<TlsAcceptor>,
+) {
     // TLS handshake on top of the connection using the TlsAcceptor
-    let stream = acceptor.accept(stream);
-    if stream.is_err() {
-        error!("TLS handshake failed with error: {}", stream.err().unwrap());
-    } else {
-        info!("TLS client connection accepted");
-        let mut u = ConnectedUser::anonymous(access_control, Connection::new(stream.unwrap()));
-        match handle_client(&mut u) {
-            Ok(_) => info!("Client connection closed"),
-            Err(e) => error!("Error while handling client connection: {}", e),
+    match acceptor.accept(stream) {
+        Ok(stream) => {
+            info!("TLS client connection accepted");
+            let conn = Connection::new(stream);
+            if let Err(e) = conn.set_timeout(sessions.idle_timeout()) {
+                error!("Could not set the connection timeout: {}", e);
+                return;
+            }
+            let mut u = ConnectedUser::anonymous(
+                store,
+                access_control,
+                throttle,
+                sessions,
+                audit_log,
+                conn,
+            );
+            match handle_client(&mut u) {
+                Ok(_) => info!("Client connection closed"),
+                Err(e) => error!("Error while handling client connection: {}", e),
+            }
         }
+        Err(e) => error!("TLS handshake failed with error: {}", e),
     }
 }
 
@@ -113,10 +176,83 @@ fn tls_config(cert_file: &str, key_file: &str) -> Arc<TlsAcceptor> {
 async fn main() {
     init_logger();
     trace!("Main server");
+
+    // Maintenance commands run instead of the server
+    let args: Vec<String> = env::args().skip(1).collect();
+    match args.first().map(String::as_str) {
+        Some("import-ron") => {
+            let path = args.get(1).map_or(database::DB_PATH, String::as_str);
+            if let Err(e) = database::import_ron(path) {
+                error!("Import failed: {}", e);
+                process::exit(1);
+            }
+            return;
+        }
+        Some("rotate-key") => {
+            match args.get(1) {
+                Some(path) => {
+                    if let Err(e) = database::rotate_key(path) {
+                        error!("Key rotation failed: {}", e);
+                        process::exit(1);
+                    }
+                }
+                None => {
+                    error!("Usage: lab3_server rotate-key <new key file>");
+                    process::exit(1);
+                }
+            }
+            return;
+        }
+        Some("verify-audit") => {
+            let path = args.get(1).map_or(audit::AUDIT_PATH, String::as_str);
+            match audit::verify(path) {
+                Ok(head) => info!(
+                    "{} is intact: {} record(s), last hash {}",
+                    path, head.count, head.hash
+                ),
+                Err(e) => {
+                    error!("Audit log verification failed: {}", e);
+                    process::exit(1);
+                }
+            }
+            return;
+        }
+        Some(command) => {
+            error!("Unknown command {}", command);
+            process::exit(1);
+        }
+        None => {}
+    }
+
     // Start TLS server and wait for new connections
     let acceptor = tls_config(CERT_PATH, KEY_PATH);
     let listener = TcpListener::bind(SERVER_IP).unwrap();
-    let access_control = Arc::new(AccessController::new().await.unwrap());
+    let store = match database::open_from_env() {
+        Ok(store) => store,
+        Err(e) => {
+            error!(
+                "Refusing to start, the user store could not be opened: {}",
+                e
+            );
+            process::exit(1);
+        }
+    };
+    let audit_log = match AuditLog::open(audit::AUDIT_PATH) {
+        Ok(audit_log) => Arc::new(audit_log),
+        Err(e) => {
+            error!("Refusing to start, the audit log is not intact: {}", e);
+            process::exit(1);
+        }
+    };
+    let head = audit_log.head();
+    info!(
+        "Audit log has {} record(s), last hash {}",
+        head.count, head.hash
+    );
+    let access_control = Arc::new(AccessController::new(store.as_ref()).await.unwrap());
+    let throttle = Arc::new(Throttle::new());
+    let sessions = Arc::new(SessionManager::new(SessionConfig::from_env()));
+    password::init();
     info!("Server started");
 
     // Handles new connection, negotiate TLS and call handle_client
@@ -124,9 +260,21 @@ async fn main() {
         match stream {
             Ok(stream) => {
                 let acceptor = acceptor.clone();
+                let store = store.clone();
                 let access_control = access_control.clone();
+                let throttle = throttle.clone();
+                let sessions = sessions.clone();
+                let audit_log = audit_log.clone();
                 thread::spawn(move || {
-                    accept(stream, access_control, acceptor);
+                    accept(
+                        stream,
+                        store,
+                        access_control,
+                        throttle,
+                        sessions,
+                        audit_log,
+                        acceptor,
+                    );
                 });
             }
             Err(e) => {
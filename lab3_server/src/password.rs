@@ -0,0 +1,156 @@
+/// This file is used to hash and verify the passwords stored in the database
+///
+/// Passwords are stored as Argon2id PHC strings, e.g.
+/// `$argon2id$v=19$m=19456,t=2,p=1$<salt>$<hash>`, so the salt and the cost
+/// parameters live next to the hash. Records written before hashing was
+/// introduced still hold the plaintext password and are upgraded on the next
+/// successful login.
+use argon2::password_hash::rand_core::OsRng;
+use argon2::password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+use argon2::{Algorithm, Argon2, Params, Version};
+use lazy_static::lazy_static;
+use rand::seq::SliceRandom;
+use rand::Rng;
+use std::env;
+use std::error::Error;
+use subtle::ConstantTimeEq;
+use validation::PASSWORD_SPECIAL_CHARS;
+
+// Default cost parameters (OWASP recommendation for Argon2id)
+const DEFAULT_M_COST: u32 = 19 * 1024;
+const DEFAULT_T_COST: u32 = 2;
+const DEFAULT_P_COST: u32 = 1;
+
+const TEMPORARY_LENGTH: usize = 16;
+// Each class, and a special character, is needed to pass the password
+// validation
+const TEMPORARY_CLASSES: [&[u8]; 3] = [
+    b"abcdefghijkmnpqrstuvwxyz",
+    b"ABCDEFGHJKLMNPQRSTUVWXYZ",
+    b"23456789",
+];
+
+lazy_static! {
+    static ref PARAMS: Params = Params::new(
+        cost_from_env("LAB3_ARGON2_M_COST", DEFAULT_M_COST),
+        cost_from_env("LAB3_ARGON2_T_COST", DEFAULT_T_COST),
+        cost_from_env("LAB3_ARGON2_P_COST", DEFAULT_P_COST),
+        None,
+    )
+    .expect("Invalid Argon2 cost parameters");
+
+    // Verified against when the user does not exist, so that the response time
+    // does not tell whether a username is valid
+    static ref DUMMY_HASH: String = hash("dummy password").expect("Could not hash dummy password");
+}
+
+/// Computes the dummy hash up front so the first unknown user is not slower
+pub fn init() {
+    lazy_static::initialize(&DUMMY_HASH);
+}
+
+fn cost_from_env(var: &str, default: u32) -> u32 {
+    match env::var(var) {
+        Ok(v) => v.parse().unwrap_or_else(|_| {
+            warn!("Ignoring invalid value {} for {}", v, var);
+            default
+        }),
+        Err(_) => default,
+    }
+}
+
+fn argon2() -> Argon2<'static> {
+    Argon2::new(Algorithm::Argon2id, Version::V0x13, PARAMS.clone())
+}
+
+/// Hashes a password with a fresh random salt and returns its PHC string
+pub fn hash(password: &str) -> Result<String, Box<dyn Error>> {
+    let salt = SaltString::generate(&mut OsRng);
+    let hash = argon2()
+        .hash_password(password.as_bytes(), &salt)
+        .map_err(|e| format!("Could not hash password: {}", e))?;
+    Ok(hash.to_string())
+}
+
+/// Checks a password against a stored PHC string, or against a legacy
+/// plaintext record if the stored value is not a PHC string
+pub fn verify(stored: &str, password: &str) -> bool {
+    match PasswordHash::new(stored) {
+        Ok(hash) => argon2().verify_password(password.as_bytes(), &hash).is_ok(),
+        Err(_) => {
+            // Legacy records still go through Argon2 so they take as long as
+            // the other ones
+            verify_dummy(password);
+            stored.as_bytes().ct_eq(password.as_bytes()).into()
+        }
+    }
+}
+
+/// Performs the same work as `verify` for a user that does not exist.
+/// Always fails.
+pub fn verify_dummy(password: &str) -> bool {
+    verify(&DUMMY_HASH, password);
+    false
+}
+
+/// Whether the stored value should be replaced by a fresh hash, either because
+/// it is a legacy plaintext record or because the cost parameters changed
+pub fn needs_rehash(stored: &str) -> bool {
+    match PasswordHash::new(stored) {
+        Ok(hash) => match Params::try_from(&hash) {
+            Ok(params) => {
+                hash.algorithm != Algorithm::Argon2id.ident()
+                    || params.m_cost() != PARAMS.m_cost()
+                    || params.t_cost() != PARAMS.t_cost()
+                    || params.p_cost() != PARAMS.p_cost()
+            }
+            Err(_) => true,
+        },
+        Err(_) => true,
+    }
+}
+
+/// Generates a random password to be given to a user whose password was reset
+pub fn generate_temporary() -> String {
+    let mut rng = rand::rngs::OsRng;
+    // The special characters accepted by the validation, but the space which
+    // is easily lost when the password is copied
+    let special: Vec<u8> = PASSWORD_SPECIAL_CHARS
+        .bytes()
+        .filter(|c| *c != b' ')
+        .collect();
+    let classes: Vec<&[u8]> = TEMPORARY_CLASSES
+        .into_iter()
+        .chain([&special[..]])
+        .collect();
+    let all: Vec<u8> = classes.concat();
+
+    let mut chars: Vec<u8> = classes
+        .iter()
+        .map(|class| class[rng.gen_range(0..class.len())])
+        .collect();
+    while chars.len() < TEMPORARY_LENGTH {
+        chars.push(all[rng.gen_range(0..all.len())]);
+    }
+    chars.shuffle(&mut rng);
+
+    chars.into_iter().map(char::from).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use validation::Validator;
+
+    #[test]
+    fn temporary_passwords_are_valid() {
+        for _ in 0..10_000 {
+            let password = generate_temporary();
+            assert!(
+                Validator::validate_password(&password).is_ok(),
+                "{} is not a valid password",
+                password
+            );
+        }
+    }
+}
@@ -0,0 +1,145 @@
+/// This file is used to keep track of the logged in users across connections
+///
+/// A successful login creates a session identified by a random token. The
+/// client keeps the token and can resume the session on a new connection until
+/// it expires or is revoked. A session expires when it has not been used for
+/// the idle timeout, or at the end of its lifetime whatever its use.
+use rand::rngs::OsRng;
+use rand::RngCore;
+use std::collections::HashMap;
+use std::env;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+const TOKEN_SIZE: usize = 32;
+const DEFAULT_IDLE_TIMEOUT: u64 = 15 * 60;
+const DEFAULT_LIFETIME: u64 = 8 * 60 * 60;
+
+pub struct SessionConfig {
+    pub idle_timeout: Duration,
+    pub lifetime: Duration,
+}
+
+impl SessionConfig {
+    /// Reads the timeouts, in seconds, from `LAB3_IDLE_TIMEOUT` and
+    /// `LAB3_SESSION_LIFETIME`
+    pub fn from_env() -> Self {
+        Self {
+            idle_timeout: secs_from_env("LAB3_IDLE_TIMEOUT", DEFAULT_IDLE_TIMEOUT),
+            lifetime: secs_from_env("LAB3_SESSION_LIFETIME", DEFAULT_LIFETIME),
+        }
+    }
+}
+
+fn secs_from_env(var: &str, default: u64) -> Duration {
+    let secs = match env::var(var) {
+        Ok(v) => v.parse().unwrap_or_else(|_| {
+            warn!("Ignoring invalid value {} for {}", v, var);
+            default
+        }),
+        Err(_) => default,
+    };
+    Duration::from_secs(secs)
+}
+
+struct Session {
+    username: String,
+    created: Instant,
+    last_seen: Instant,
+}
+
+pub struct SessionManager {
+    sessions: Mutex<HashMap<String, Session>>,
+    config: SessionConfig,
+}
+
+impl SessionManager {
+    pub fn new(config: SessionConfig) -> Self {
+        Self {
+            sessions: Mutex::new(HashMap::new()),
+            config,
+        }
+    }
+
+    pub fn idle_timeout(&self) -> Duration {
+        self.config.idle_timeout
+    }
+
+    fn is_expired(&self, session: &Session, now: Instant) -> bool {
+        now.duration_since(session.last_seen) > self.config.idle_timeout
+            || now.duration_since(session.created) > self.config.lifetime
+    }
+
+    /// Opens a session for the user and returns its token. The expired
+    /// sessions are dropped, the clients that left without logging out never
+    /// resume them.
+    pub fn create(&self, username: &str) -> String {
+        let mut bytes = [0u8; TOKEN_SIZE];
+        OsRng.fill_bytes(&mut bytes);
+        let token: String = bytes.iter().map(|b| format!("{:02x}", b)).collect();
+
+        let now = Instant::now();
+        let session = Session {
+            username: username.to_string(),
+            created: now,
+            last_seen: now,
+        };
+        let mut sessions = self.sessions.lock().unwrap();
+        sessions.retain(|_, s| !self.is_expired(s, now));
+        sessions.insert(token.clone(), session);
+        token
+    }
+
+    /// Returns the user of a valid session and marks it as used. Expired
+    /// sessions are dropped.
+    pub fn resume(&self, token: &str) -> Option<String> {
+        let now = Instant::now();
+        let mut sessions = self.sessions.lock().unwrap();
+        match sessions.get_mut(token) {
+            Some(s) if !self.is_expired(s, now) => {
+                s.last_seen = now;
+                Some(s.username.clone())
+            }
+            Some(_) => {
+                sessions.remove(token);
+                None
+            }
+            None => None,
+        }
+    }
+
+    pub fn revoke(&self, token: &str) {
+        self.sessions.lock().unwrap().remove(token);
+    }
+
+    /// Revokes all the sessions of a user but the given one, returns how many
+    /// were revoked
+    pub fn revoke_user(&self, username: &str, except: Option<&str>) -> usize {
+        let mut sessions = self.sessions.lock().unwrap();
+        let before = sessions.len();
+        sessions.retain(|token, s| s.username != username || Some(token.as_str()) == except);
+        before - sessions.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    #[test]
+    fn expired_sessions_are_dropped() {
+        let sessions = SessionManager::new(SessionConfig {
+            idle_timeout: Duration::from_millis(50),
+            lifetime: Duration::from_secs(60),
+        });
+        // Neither is resumed nor revoked, as when a client disconnects
+        sessions.create("alice");
+        sessions.create("bob");
+        thread::sleep(Duration::from_millis(100));
+
+        let token = sessions.create("carol");
+        assert_eq!(sessions.sessions.lock().unwrap().len(), 1);
+        assert_eq!(sessions.resume(&token).as_deref(), Some("carol"));
+    }
+}
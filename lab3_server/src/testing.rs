@@ -0,0 +1,58 @@
+/// This file holds the fixtures shared by the tests of the server
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use tempfile::TempDir;
+use tokio::runtime::Runtime;
+
+use crate::access_control::AccessController;
+use crate::database::UserStore;
+use crate::user::{UserAccount, UserRole};
+
+pub const PASSWORD: &str = "Secr3t*pass";
+
+pub fn account(username: &str, role: UserRole) -> UserAccount {
+    UserAccount::new(
+        username.to_string(),
+        PASSWORD.to_string(),
+        "079-111-2222".to_string(),
+        role,
+    )
+    .unwrap()
+}
+
+/// An access controller whose policy is kept in a temporary directory
+pub struct TestPolicy {
+    pub ac: Arc<AccessController>,
+    pub path: PathBuf,
+    // Runs the async enforcer API, must outlive the controller
+    runtime: Runtime,
+    _dir: TempDir,
+}
+
+impl TestPolicy {
+    pub fn new(store: &dyn UserStore) -> Self {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("policy.csv");
+        let runtime = Runtime::new().unwrap();
+        let ac = open(&runtime, &path, store);
+        Self {
+            ac,
+            path,
+            runtime,
+            _dir: dir,
+        }
+    }
+
+    /// Loads the policy file again, as the server does when it starts
+    pub fn reopen(&mut self, store: &dyn UserStore) {
+        self.ac = open(&self.runtime, &self.path, store);
+    }
+}
+
+fn open(runtime: &Runtime, path: &Path, store: &dyn UserStore) -> Arc<AccessController> {
+    let ac = runtime
+        .block_on(AccessController::open(path.to_str().unwrap(), store))
+        .unwrap();
+    Arc::new(ac)
+}
@@ -0,0 +1,343 @@
+/// This file is used to slow down brute-force attacks on the login
+///
+/// Failed logins are counted per username and per peer address. After a few
+/// free attempts, each new failure doubles the time before the next attempt is
+/// accepted, and too many failures lock the account (or address) for a while.
+///
+/// An attempt is let through and counted by the same step, and counts as a
+/// failure until it ends, so attempts made at once on several connections are
+/// throttled as if they had been made one after the other.
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use utils::ErrorMessage;
+
+pub const FREE_ATTEMPTS: u32 = 3;
+const BASE_DELAY: Duration = Duration::from_secs(1);
+const MAX_DELAY: Duration = Duration::from_secs(5 * 60);
+const ACCOUNT_LOCKOUT_THRESHOLD: u32 = 10;
+const ADDRESS_LOCKOUT_THRESHOLD: u32 = 50;
+const LOCKOUT_DURATION: Duration = Duration::from_secs(15 * 60);
+// Failures older than this are forgotten
+const FAILURE_MEMORY: Duration = Duration::from_secs(60 * 60);
+
+struct Failures {
+    count: u32,
+    // Attempts under way, counted as failures until they end
+    in_flight: u32,
+    // Start of the last attempt, or end of the last failure
+    last_attempt: Instant,
+    locked_until: Option<Instant>,
+}
+
+impl Failures {
+    fn new(now: Instant) -> Self {
+        Self {
+            count: 0,
+            in_flight: 0,
+            last_attempt: now,
+            locked_until: None,
+        }
+    }
+
+    /// Failures the next attempt must wait for, the ones under way included
+    fn pending(&self) -> u32 {
+        self.count + self.in_flight
+    }
+
+    fn delay(&self) -> Duration {
+        let pending = self.pending();
+        if pending < FREE_ATTEMPTS {
+            Duration::ZERO
+        } else {
+            let exp = (pending - FREE_ATTEMPTS).min(16);
+            (BASE_DELAY * 2u32.pow(exp)).min(MAX_DELAY)
+        }
+    }
+
+    fn is_locked(&self, now: Instant) -> bool {
+        matches!(self.locked_until, Some(t) if t > now)
+    }
+
+    fn is_expired(&self, now: Instant) -> bool {
+        if self.in_flight > 0 {
+            return false;
+        }
+        match self.locked_until {
+            Some(t) => t <= now,
+            None => now.duration_since(self.last_attempt) > FAILURE_MEMORY,
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.count == 0 && self.in_flight == 0 && self.locked_until.is_none()
+    }
+}
+
+/// Failure counters for one kind of key (username or address)
+struct Tracker<K> {
+    failures: Mutex<HashMap<K, Failures>>,
+    lockout_threshold: u32,
+}
+
+impl<K: Hash + Eq + Clone> Tracker<K> {
+    fn new(lockout_threshold: u32) -> Self {
+        Self {
+            failures: Mutex::new(HashMap::new()),
+            lockout_threshold,
+        }
+    }
+
+    /// Lets an attempt through if the failures allow it, it is then under way
+    /// until `end` is called
+    fn begin(&self, key: &K, now: Instant) -> Result<(), Locked> {
+        let mut failures = self.failures.lock().unwrap();
+        if failures.get(key).is_some_and(|f| f.is_expired(now)) {
+            failures.remove(key);
+        }
+        let f = failures
+            .entry(key.clone())
+            .or_insert_with(|| Failures::new(now));
+        if f.is_locked(now) {
+            return Err(Locked::LockedOut);
+        }
+        if f.pending() >= self.lockout_threshold || f.last_attempt + f.delay() > now {
+            return Err(Locked::Backoff);
+        }
+        f.in_flight += 1;
+        f.last_attempt = now;
+        Ok(())
+    }
+
+    /// Ends an attempt let through by `begin`. Returns true if this failure
+    /// locked the key.
+    fn end(&self, key: &K, failed: bool, now: Instant) -> bool {
+        let mut failures = self.failures.lock().unwrap();
+        let f = failures
+            .entry(key.clone())
+            .or_insert_with(|| Failures::new(now));
+        f.in_flight = f.in_flight.saturating_sub(1);
+
+        let mut locked = false;
+        if failed {
+            f.count += 1;
+            f.last_attempt = now;
+            if f.count >= self.lockout_threshold && !f.is_locked(now) {
+                f.locked_until = Some(now + LOCKOUT_DURATION);
+                locked = true;
+            }
+        }
+        if f.is_empty() {
+            failures.remove(key);
+        }
+        locked
+    }
+
+    /// Forgets the failures, not the attempts under way. Returns true if there
+    /// were any.
+    fn clear(&self, key: &K) -> bool {
+        let mut failures = self.failures.lock().unwrap();
+        match failures.get_mut(key) {
+            Some(f) => {
+                let cleared = f.count > 0 || f.locked_until.is_some();
+                f.count = 0;
+                f.locked_until = None;
+                if f.is_empty() {
+                    failures.remove(key);
+                }
+                cleared
+            }
+            None => false,
+        }
+    }
+}
+
+enum Locked {
+    Backoff,
+    LockedOut,
+}
+
+pub struct Throttle {
+    accounts: Tracker<String>,
+    addresses: Tracker<IpAddr>,
+}
+
+impl Throttle {
+    pub fn new() -> Self {
+        Self {
+            accounts: Tracker::new(ACCOUNT_LOCKOUT_THRESHOLD),
+            addresses: Tracker::new(ADDRESS_LOCKOUT_THRESHOLD),
+        }
+    }
+
+    /// Lets a login attempt for this username from this address through, if
+    /// the failures allow it. It counts as a failure until it ends.
+    pub fn begin(self: &Arc<Self>, username: &str, addr: IpAddr) -> Result<Attempt, ErrorMessage> {
+        let now = Instant::now();
+        match self.accounts.begin(&username.to_string(), now) {
+            Err(Locked::LockedOut) => {
+                warn!("Login attempt on locked account {} from {}", username, addr);
+                return Err(ErrorMessage::ErrorAccountLocked);
+            }
+            Err(Locked::Backoff) => {
+                warn!("Login attempt for {} from {} too soon", username, addr);
+                return Err(ErrorMessage::ErrorTooManyAttempts);
+            }
+            Ok(_) => {}
+        }
+
+        if self.addresses.begin(&addr, now).is_err() {
+            warn!("Login attempt from throttled address {}", addr);
+            self.accounts.end(&username.to_string(), false, now);
+            return Err(ErrorMessage::ErrorTooManyAttempts);
+        }
+        Ok(Attempt {
+            throttle: self.clone(),
+            username: username.to_string(),
+            addr,
+            ended: false,
+        })
+    }
+
+    fn end(&self, username: &str, addr: IpAddr, failed: bool) {
+        let now = Instant::now();
+        if self.accounts.end(&username.to_string(), failed, now) {
+            warn!(
+                "Account {} locked for {} seconds after too many failed logins",
+                username,
+                LOCKOUT_DURATION.as_secs()
+            );
+        }
+        if self.addresses.end(&addr, failed, now) {
+            warn!(
+                "Address {} locked for {} seconds after too many failed logins",
+                addr,
+                LOCKOUT_DURATION.as_secs()
+            );
+        }
+    }
+
+    pub fn is_locked(&self, username: &str) -> bool {
+        let failures = self.accounts.failures.lock().unwrap();
+        matches!(failures.get(username), Some(f) if f.is_locked(Instant::now()))
+    }
+
+    /// Clears the failures of an account, returns true if there were any
+    pub fn unlock(&self, username: &str) -> bool {
+        self.accounts.clear(&username.to_string())
+    }
+}
+
+/// A login attempt let through by `Throttle::begin`. Dropping it without
+/// telling how it went, e.g. on an error, ends it without counting it.
+pub struct Attempt {
+    throttle: Arc<Throttle>,
+    username: String,
+    addr: IpAddr,
+    ended: bool,
+}
+
+impl Attempt {
+    pub fn failed(mut self) {
+        self.end(true);
+    }
+
+    /// Only the failures of the account are cleared. Those of the address
+    /// expire on their own, or logging in to one's own account between
+    /// guesses would allow trying passwords on every other account.
+    pub fn succeeded(mut self) {
+        self.throttle.accounts.clear(&self.username);
+        self.end(false);
+    }
+
+    fn end(&mut self, failed: bool) {
+        if !self.ended {
+            self.ended = true;
+            self.throttle.end(&self.username, self.addr, failed);
+        }
+    }
+}
+
+impl Drop for Attempt {
+    fn drop(&mut self) {
+        self.end(false);
+    }
+}
+
+impl Default for Throttle {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    fn fail(throttle: &Arc<Throttle>, username: &str, addr: IpAddr) {
+        throttle.begin(username, addr).unwrap().failed();
+    }
+
+    fn succeed(throttle: &Arc<Throttle>, username: &str, addr: IpAddr) {
+        throttle.begin(username, addr).unwrap().succeeded();
+    }
+
+    #[test]
+    fn success_does_not_reset_the_address() {
+        let throttle = Arc::new(Throttle::new());
+        let addr = IpAddr::V4(Ipv4Addr::LOCALHOST);
+
+        for i in 0..FREE_ATTEMPTS {
+            succeed(&throttle, "attacker", addr);
+            fail(&throttle, &format!("victim{}", i), addr);
+        }
+
+        assert!(matches!(
+            throttle.begin("other_victim", addr),
+            Err(ErrorMessage::ErrorTooManyAttempts)
+        ));
+        assert!(matches!(
+            throttle.begin("attacker", addr),
+            Err(ErrorMessage::ErrorTooManyAttempts)
+        ));
+    }
+
+    #[test]
+    fn success_resets_the_account() {
+        let throttle = Arc::new(Throttle::new());
+        let addr = IpAddr::V4(Ipv4Addr::LOCALHOST);
+        let other = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1));
+
+        for _ in 1..FREE_ATTEMPTS {
+            fail(&throttle, "alice", addr);
+        }
+        succeed(&throttle, "alice", addr);
+        for _ in 1..FREE_ATTEMPTS {
+            fail(&throttle, "alice", other);
+        }
+
+        assert!(throttle.begin("alice", other).is_ok());
+    }
+
+    #[test]
+    fn attempts_under_way_count_as_failures() {
+        let throttle = Arc::new(Throttle::new());
+        let addr = IpAddr::V4(Ipv4Addr::LOCALHOST);
+
+        let attempts: Vec<Attempt> = (0..FREE_ATTEMPTS)
+            .map(|_| throttle.begin("alice", addr).unwrap())
+            .collect();
+        assert!(matches!(
+            throttle.begin("alice", addr),
+            Err(ErrorMessage::ErrorTooManyAttempts)
+        ));
+
+        // Ended without telling how it went, they are not counted anymore
+        drop(attempts);
+        assert!(throttle.begin("alice", addr).is_ok());
+    }
+}
@@ -0,0 +1,117 @@
+/// This file is used for the second authentication factor, a TOTP (RFC 6238)
+/// code from an authenticator app or one of the single-use recovery codes
+/// given at enrollment
+use rand::rngs::OsRng;
+use rand::{Rng, RngCore};
+use sha2::{Digest, Sha256};
+use std::error::Error;
+use std::time::{SystemTime, UNIX_EPOCH};
+use subtle::ConstantTimeEq;
+use totp_rs::{Algorithm, Secret, TOTP};
+
+const ISSUER: &str = "RESIGN";
+const SECRET_SIZE: usize = 20;
+const RECOVERY_CODES: usize = 8;
+const RECOVERY_CODE_ALPHABET: &[u8] = b"abcdefghijkmnpqrstuvwxyz23456789";
+const STEP: u64 = 30;
+// Codes of the previous and next time steps are accepted too, in case the
+// clock of the phone is a bit off
+const SKEW: u64 = 1;
+
+/// Generates a new base32 encoded secret
+pub fn generate_secret() -> String {
+    let mut bytes = [0u8; SECRET_SIZE];
+    OsRng.fill_bytes(&mut bytes);
+    Secret::Raw(bytes.to_vec()).to_encoded().to_string()
+}
+
+fn totp(secret: &str, username: &str) -> Result<TOTP, Box<dyn Error>> {
+    let secret = Secret::Encoded(secret.to_string())
+        .to_bytes()
+        .map_err(|e| format!("Invalid TOTP secret: {:?}", e))?;
+    Ok(TOTP::new(
+        Algorithm::SHA1,
+        6,
+        SKEW as u8,
+        STEP,
+        secret,
+        Some(ISSUER.to_string()),
+        username.to_string(),
+    )?)
+}
+
+/// URI to be displayed to the user, or turned into a QR code, for the
+/// authenticator app
+pub fn otpauth_uri(secret: &str, username: &str) -> Result<String, Box<dyn Error>> {
+    Ok(totp(secret, username)?.get_url())
+}
+
+/// Checks a TOTP code and returns the time step it belongs to. A code is only
+/// accepted once (RFC 6238, section 5.2): codes of steps at or before
+/// `last_step`, the step of the last code accepted, are rejected.
+pub fn check_code(
+    secret: &str,
+    username: &str,
+    code: &str,
+    last_step: Option<u64>,
+) -> Result<Option<u64>, Box<dyn Error>> {
+    let totp = totp(secret, username)?;
+    let current = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs() / STEP;
+
+    let first = match last_step {
+        Some(last) => (last + 1).max(current.saturating_sub(SKEW)),
+        None => current.saturating_sub(SKEW),
+    };
+    Ok((first..=current + SKEW).find(|step| {
+        let expected = totp.generate(step * STEP);
+        bool::from(expected.as_bytes().ct_eq(code.as_bytes()))
+    }))
+}
+
+/// Generates codes of the form `xxxxx-xxxxx`
+pub fn generate_recovery_codes() -> Vec<String> {
+    (0..RECOVERY_CODES)
+        .map(|_| {
+            let mut code: String = (0..10)
+                .map(|_| {
+                    let i = OsRng.gen_range(0..RECOVERY_CODE_ALPHABET.len());
+                    RECOVERY_CODE_ALPHABET[i] as char
+                })
+                .collect();
+            code.insert(5, '-');
+            code
+        })
+        .collect()
+}
+
+/// Recovery codes are random enough to be stored as plain SHA-256 hashes
+pub fn hash_recovery_code(code: &str) -> String {
+    Sha256::digest(code.as_bytes())
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn code_is_accepted_once() {
+        let secret = generate_secret();
+        let code = totp(&secret, "alice").unwrap().generate_current().unwrap();
+
+        let step = check_code(&secret, "alice", &code, None).unwrap();
+        assert!(step.is_some());
+        assert_eq!(check_code(&secret, "alice", &code, step).unwrap(), None);
+    }
+
+    #[test]
+    fn wrong_code_is_rejected() {
+        let secret = generate_secret();
+        let code = totp(&secret, "alice").unwrap().generate_current().unwrap();
+        let wrong = format!("{:06}", (code.parse::<u32>().unwrap() + 1) % 1_000_000);
+
+        assert_eq!(check_code(&secret, "alice", &wrong, None).unwrap(), None);
+    }
+}
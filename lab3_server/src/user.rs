@@ -1,50 +1,325 @@
 /// This file is used to store and retrieve user accounts from the database
 ///
 /// Tasks todo: - Potential improvements
+use crate::password;
+use crate::two_factor;
+use protocol::AccountRevision;
+pub use protocol::UserRole;
 use serde::{Deserialize, Serialize};
-use strum_macros::Display;
+use std::error::Error;
+use std::time::{SystemTime, UNIX_EPOCH};
+use strum_macros::{Display, EnumString};
 
-#[derive(Serialize, Deserialize, Clone, Debug, Display, Hash, Copy)]
-pub enum UserRole {
-    #[strum(serialize = "anon")]
-    Anon,
-    #[strum(serialize = "standard_user")]
-    StandardUser,
-    #[strum(serialize = "hr")]
-    HR,
+// Number of previous passwords that cannot be reused
+const PASSWORD_HISTORY: usize = 5;
+// Number of changes kept in the history of an account
+const MAX_REVISIONS: usize = 50;
+
+/// Fields of an account whose changes are kept in its history
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Display, EnumString, PartialEq, Eq)]
+pub enum AccountField {
+    #[strum(serialize = "phone_number")]
+    PhoneNumber,
+    #[strum(serialize = "role")]
+    Role,
+}
+
+/// A change of an account, identified by the version of the account it
+/// created
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct Revision {
+    pub version: u64,
+    // Seconds since the Unix epoch
+    pub time: u64,
+    pub actor: String,
+    pub field: AccountField,
+    pub old: String,
+    pub new: String,
 }
 
-#[derive(Serialize, Deserialize, Clone, Debug)]
+impl From<&Revision> for AccountRevision {
+    fn from(r: &Revision) -> Self {
+        Self {
+            version: r.version,
+            time: r.time,
+            actor: r.actor.clone(),
+            field: r.field.to_string(),
+            old: r.old.clone(),
+            new: r.new.clone(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
 pub struct UserAccount {
     pub username: String,
-    password: String,
+    // PHC string, or the plaintext password for records written before hashing
+    #[serde(alias = "password")]
+    password_hash: String,
     pub phone_number: String,
     pub role: UserRole,
+    // Base32 TOTP secret, set once two-factor authentication is enabled
+    #[serde(default)]
+    totp_secret: Option<String>,
+    // Time step of the last TOTP code accepted, so it cannot be used again
+    #[serde(default)]
+    totp_last_step: Option<u64>,
+    // Hashes of the recovery codes not used yet
+    #[serde(default)]
+    recovery_codes: Vec<String>,
+    // Hashes of the previous passwords, most recent first
+    #[serde(default)]
+    password_history: Vec<String>,
+    // Set when HR reset the password, until the user chooses a new one
+    #[serde(default)]
+    must_change_password: bool,
+    // Set by HR, the account is kept but cannot log in
+    #[serde(default)]
+    deactivated: bool,
+    // Changes of the phone number and role, oldest first
+    #[serde(default)]
+    revisions: Vec<Revision>,
+    // Incremented by the store on each write, to detect concurrent changes
+    #[serde(default)]
+    version: u64,
 }
 
-impl UserAccount {
-    pub fn new(username: String, password: String, phone_number: String, role: UserRole) -> Self {
+/// Every field of an account, for the stores that do not go through serde
+pub struct AccountRecord {
+    pub username: String,
+    pub password_hash: String,
+    pub phone_number: String,
+    pub role: UserRole,
+    pub totp_secret: Option<String>,
+    pub totp_last_step: Option<u64>,
+    pub recovery_codes: Vec<String>,
+    pub password_history: Vec<String>,
+    pub must_change_password: bool,
+    pub deactivated: bool,
+    pub revisions: Vec<Revision>,
+    pub version: u64,
+}
+
+impl From<AccountRecord> for UserAccount {
+    fn from(r: AccountRecord) -> Self {
         Self {
+            username: r.username,
+            password_hash: r.password_hash,
+            phone_number: r.phone_number,
+            role: r.role,
+            totp_secret: r.totp_secret,
+            totp_last_step: r.totp_last_step,
+            recovery_codes: r.recovery_codes,
+            password_history: r.password_history,
+            must_change_password: r.must_change_password,
+            deactivated: r.deactivated,
+            revisions: r.revisions,
+            version: r.version,
+        }
+    }
+}
+
+impl From<&UserAccount> for AccountRecord {
+    fn from(u: &UserAccount) -> Self {
+        Self {
+            username: u.username.clone(),
+            password_hash: u.password_hash.clone(),
+            phone_number: u.phone_number.clone(),
+            role: u.role,
+            totp_secret: u.totp_secret.clone(),
+            totp_last_step: u.totp_last_step,
+            recovery_codes: u.recovery_codes.clone(),
+            password_history: u.password_history.clone(),
+            must_change_password: u.must_change_password,
+            deactivated: u.deactivated,
+            revisions: u.revisions.clone(),
+            version: u.version,
+        }
+    }
+}
+
+impl UserAccount {
+    pub fn new(
+        username: String,
+        password: String,
+        phone_number: String,
+        role: UserRole,
+    ) -> Result<Self, Box<dyn Error>> {
+        Ok(Self {
             username,
-            password,
+            password_hash: password::hash(&password)?,
             phone_number,
             role,
-        }
+            totp_secret: None,
+            totp_last_step: None,
+            recovery_codes: Vec::new(),
+            password_history: Vec::new(),
+            must_change_password: false,
+            deactivated: false,
+            revisions: Vec::new(),
+            version: 0,
+        })
     }
 
     pub fn username(&self) -> &str {
         &self.username
     }
 
-    pub fn password(&self) -> &str {
-        &self.password
+    pub fn version(&self) -> u64 {
+        self.version
+    }
+
+    /// Copy of the account as written by a store
+    pub fn next_version(&self) -> Self {
+        Self {
+            version: self.version + 1,
+            ..self.clone()
+        }
+    }
+
+    pub fn password_hash(&self) -> &str {
+        &self.password_hash
+    }
+
+    pub fn set_password(&mut self, password: &str) -> Result<(), Box<dyn Error>> {
+        self.password_hash = password::hash(password)?;
+        Ok(())
+    }
+
+    /// Replaces the password chosen by the user, keeping the old one in the
+    /// history
+    pub fn change_password(&mut self, password: &str) -> Result<(), Box<dyn Error>> {
+        let old = std::mem::replace(&mut self.password_hash, password::hash(password)?);
+        self.password_history.insert(0, old);
+        self.password_history.truncate(PASSWORD_HISTORY);
+        self.must_change_password = false;
+        Ok(())
+    }
+
+    /// Sets a temporary password that must be changed on the next login
+    pub fn reset_password(&mut self, temporary: &str) -> Result<(), Box<dyn Error>> {
+        self.change_password(temporary)?;
+        self.must_change_password = true;
+        Ok(())
+    }
+
+    pub fn must_change_password(&self) -> bool {
+        self.must_change_password
+    }
+
+    /// Whether the password is the current one or one of the previous ones
+    pub fn is_recent_password(&self, password: &str) -> bool {
+        std::iter::once(&self.password_hash)
+            .chain(self.password_history.iter())
+            .any(|hash| password::verify(hash, password))
     }
 
     pub fn role(&self) -> &UserRole {
         &self.role
     }
 
-    pub fn set_phone_number(&mut self, phone_number: String) {
-        self.phone_number = phone_number;
+    /// Changes the role, `actor` is kept in the history
+    pub fn set_role(&mut self, role: UserRole, actor: &str) {
+        let old = std::mem::replace(&mut self.role, role);
+        self.add_revision(actor, AccountField::Role, old.to_string(), role.to_string());
+    }
+
+    /// Changes the phone number, `actor` is kept in the history
+    pub fn set_phone_number(&mut self, phone_number: String, actor: &str) {
+        let old = std::mem::replace(&mut self.phone_number, phone_number.clone());
+        self.add_revision(actor, AccountField::PhoneNumber, old, phone_number);
+    }
+
+    /// Records a change made by the next write of the account
+    fn add_revision(&mut self, actor: &str, field: AccountField, old: String, new: String) {
+        let time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_secs());
+        self.revisions.push(Revision {
+            version: self.version + 1,
+            time,
+            actor: actor.to_string(),
+            field,
+            old,
+            new,
+        });
+        if self.revisions.len() > MAX_REVISIONS {
+            self.revisions.remove(0);
+        }
+    }
+
+    /// Changes of the phone number and role, oldest first
+    pub fn revisions(&self) -> &[Revision] {
+        &self.revisions
+    }
+
+    pub fn is_active(&self) -> bool {
+        !self.deactivated
+    }
+
+    pub fn set_active(&mut self, active: bool) {
+        self.deactivated = !active;
+    }
+
+    /// Short description of the state of the account, e.g. "active, 2FA"
+    pub fn status(&self, locked: bool) -> String {
+        let mut status = vec![if self.deactivated {
+            "deactivated"
+        } else if locked {
+            "locked"
+        } else {
+            "active"
+        }];
+        if self.must_change_password {
+            status.push("password reset");
+        }
+        if self.has_two_factor() {
+            status.push("2FA");
+        }
+        status.join(", ")
+    }
+
+    pub fn has_two_factor(&self) -> bool {
+        self.totp_secret.is_some()
+    }
+
+    /// `step` is the time step of the code that confirmed the secret
+    pub fn enable_two_factor(&mut self, secret: String, step: u64, recovery_codes: &[String]) {
+        self.totp_secret = Some(secret);
+        self.totp_last_step = Some(step);
+        self.recovery_codes = recovery_codes
+            .iter()
+            .map(|c| two_factor::hash_recovery_code(c))
+            .collect();
+    }
+
+    /// Checks a TOTP code or a recovery code. Neither can be used twice, so
+    /// the account must be saved after it matched.
+    pub fn check_second_factor(&mut self, code: &str) -> Result<bool, Box<dyn Error>> {
+        let secret = match &self.totp_secret {
+            Some(secret) => secret,
+            None => return Ok(false),
+        };
+
+        if let Some(step) =
+            two_factor::check_code(secret, &self.username, code, self.totp_last_step)?
+        {
+            self.totp_last_step = Some(step);
+            return Ok(true);
+        }
+
+        let hash = two_factor::hash_recovery_code(code);
+        match self.recovery_codes.iter().position(|c| *c == hash) {
+            Some(i) => {
+                self.recovery_codes.remove(i);
+                info!(
+                    "Recovery code used by {}, {} left",
+                    self.username,
+                    self.recovery_codes.len()
+                );
+                Ok(true)
+            }
+            None => Ok(false),
+        }
     }
 }
@@ -0,0 +1,39 @@
+/// This file is used to describe the audit log queries and their results
+use serde::{Deserialize, Serialize};
+
+/// Actor of the records of actions performed without being logged in, no
+/// username can contain `<` so it is never taken for a user
+pub static ANONYMOUS: &str = "<anonymous>";
+
+/// Filters of an audit log query, those not set match every record
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct AuditQuery {
+    pub actor: Option<String>,
+    pub target: Option<String>,
+    // Name of the event, e.g. "login_failure"
+    pub event: Option<String>,
+    // Seconds since the Unix epoch, `until` is excluded
+    pub since: Option<u64>,
+    pub until: Option<u64>,
+    // Starting at 0
+    pub page: u64,
+}
+
+/// Audit record as shown to clients, without the hash chain
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct AuditEntry {
+    pub seq: u64,
+    pub time: u64,
+    pub actor: String,
+    pub event: String,
+    pub target: Option<String>,
+    pub details: String,
+}
+
+/// One page of the matching records, most recent first
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct AuditPage {
+    pub entries: Vec<AuditEntry>,
+    pub page: u64,
+    pub pages: u64,
+}
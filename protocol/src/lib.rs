@@ -0,0 +1,18 @@
+/// This file is used to define the messages exchanged by the client and the
+/// server
+///
+/// Whenever the server is ready for a new request, it sends
+/// `Response::Ready` with the banner to show. The client then sends one
+/// `Request` and the server answers with one `Response`, so every action is a
+/// single round-trip.
+mod audit;
+mod request;
+mod response;
+mod revision;
+mod user;
+
+pub use audit::{AuditEntry, AuditPage, AuditQuery, ANONYMOUS};
+pub use request::Request;
+pub use response::{Response, TwoFactorSetup};
+pub use revision::AccountRevision;
+pub use user::{PublicUser, UserRole};
@@ -0,0 +1,62 @@
+/// This file is used to describe the requests sent by the client
+use serde::{Deserialize, Serialize};
+
+use crate::{AuditQuery, UserRole};
+
+/// One action of the client, with everything the server needs to perform it.
+/// The expected answer is given for each of them, any request can also be
+/// answered with `Response::Error`.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub enum Request {
+    /// `Response::Users`
+    ShowUsers,
+    /// `Response::Ok`
+    ChangeOwnPhone { phone_number: String },
+    /// `Response::Ok`
+    ChangePhone {
+        username: String,
+        phone_number: String,
+    },
+    /// `Response::Ok`
+    AddUser {
+        username: String,
+        password: String,
+        phone_number: String,
+        role: UserRole,
+    },
+    /// `Response::LoggedIn`, or `Response::TwoFactorRequired` if a
+    /// `LoginSecondFactor` request must follow
+    Login { username: String, password: String },
+    /// `Response::LoggedIn`, only right after `Login`
+    LoginSecondFactor { code: String },
+    /// `Response::Ok`
+    Logout,
+    /// `Response::Ok`
+    UnlockUser { username: String },
+    /// `Response::TwoFactorSetup`, a `ConfirmTwoFactor` request must follow
+    EnableTwoFactor,
+    /// `Response::Ok`, only right after `EnableTwoFactor`
+    ConfirmTwoFactor { code: String },
+    /// `Response::Ok`
+    ChangeOwnPassword { current: String, new: String },
+    /// `Response::TemporaryPassword`
+    ResetPassword { username: String },
+    /// `Response::Ok`
+    DeactivateUser { username: String },
+    /// `Response::Ok`
+    ReactivateUser { username: String },
+    /// `Response::Ok`
+    DeleteUser { username: String },
+    /// `Response::Ok`
+    ChangeRole { username: String, role: UserRole },
+    /// `Response::AuditLog`
+    ShowAuditLog(AuditQuery),
+    /// `Response::History`
+    ShowUserHistory { username: String },
+    /// `Response::Ok`
+    RevertChange { username: String, version: u64 },
+    /// `Response::Ok`, sent after reconnecting to log back in
+    ResumeSession { token: String },
+    /// `Response::Ok`, then the server closes the connection
+    Exit,
+}
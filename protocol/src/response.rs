@@ -0,0 +1,51 @@
+/// This file is used to describe the responses sent by the server
+use serde::{Deserialize, Serialize};
+use utils::ErrorMessage;
+
+use crate::{AccountRevision, AuditPage, PublicUser};
+
+/// Answer to a `Request`
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub enum Response {
+    /// Sent before each request, the server is ready for it. The banner is
+    /// shown along with the menu.
+    Ready {
+        banner: String,
+    },
+    /// The action was performed and has nothing to tell
+    Ok,
+    Users(Vec<PublicUser>),
+    /// Logged in, with the token of the new session
+    LoggedIn(String),
+    /// A TOTP or recovery code must be sent to complete the login
+    TwoFactorRequired,
+    TwoFactorSetup(TwoFactorSetup),
+    /// Temporary password set by HR, to give to the user
+    TemporaryPassword(String),
+    AuditLog(AuditPage),
+    History(Vec<AccountRevision>),
+    Error(ErrorMessage),
+}
+
+impl From<ErrorMessage> for Response {
+    fn from(e: ErrorMessage) -> Self {
+        Response::Error(e)
+    }
+}
+
+impl From<Result<(), ErrorMessage>> for Response {
+    fn from(res: Result<(), ErrorMessage>) -> Self {
+        match res {
+            Ok(_) => Response::Ok,
+            Err(e) => Response::Error(e),
+        }
+    }
+}
+
+/// Sent to the user enabling two-factor authentication
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct TwoFactorSetup {
+    pub uri: String,
+    pub secret: String,
+    pub recovery_codes: Vec<String>,
+}
@@ -0,0 +1,16 @@
+/// This file is used to describe the changes of an account as they are sent
+/// to clients
+use serde::{Deserialize, Serialize};
+
+/// A change of an account, `version` identifies it to revert it
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct AccountRevision {
+    pub version: u64,
+    // Seconds since the Unix epoch
+    pub time: u64,
+    pub actor: String,
+    // Name of the changed field, e.g. "phone_number"
+    pub field: String,
+    pub old: String,
+    pub new: String,
+}
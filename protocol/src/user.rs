@@ -0,0 +1,23 @@
+/// This file is used to describe the user accounts as they are sent to clients
+use serde::{Deserialize, Serialize};
+use strum_macros::{Display, EnumString};
+
+/// Entry of the user directory. Only the fields the caller is allowed to see
+/// are set, and no secret is part of it.
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct PublicUser {
+    pub username: String,
+    pub phone_number: Option<String>,
+    pub role: Option<String>,
+    pub status: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Display, EnumString, Hash, PartialEq, Eq)]
+pub enum UserRole {
+    #[strum(serialize = "anon")]
+    Anon,
+    #[strum(serialize = "standard_user")]
+    StandardUser,
+    #[strum(serialize = "hr")]
+    HR,
+}
@@ -61,6 +61,39 @@ pub enum ErrorMessage {
     ErrorUserNotFound,
     #[strum(serialize = "User already exists")]
     ErrorUserAlreadyExists,
+    #[strum(serialize = "Too many failed attempts, please try again later")]
+    ErrorTooManyAttempts,
+    #[strum(serialize = "This account is temporarily locked, please contact HR")]
+    ErrorAccountLocked,
+    #[strum(serialize = "Your session is invalid or has expired, please log in again")]
+    ErrorInvalidSession,
+    #[strum(serialize = "Your session has expired, please log in again")]
+    ErrorSessionExpired,
+    #[strum(serialize = "Invalid authentication code")]
+    ErrorInvalidCode,
+    #[strum(
+        serialize = "Two-factor authentication is required for your account, please set it up first"
+    )]
+    ErrorTwoFactorRequired,
+    #[strum(serialize = "Two-factor authentication is already enabled")]
+    ErrorTwoFactorAlreadyEnabled,
+    #[strum(serialize = "Current password is incorrect")]
+    ErrorWrongPassword,
+    #[strum(serialize = "This password was used recently, please choose another one")]
+    ErrorPasswordReused,
+    #[strum(serialize = "Your password was reset, please change it first")]
+    ErrorPasswordChangeRequired,
+    #[strum(serialize = "This account is deactivated, please contact HR")]
+    ErrorAccountDeactivated,
+    #[strum(serialize = "The last active HR account cannot be demoted, deactivated or deleted")]
+    ErrorLastHR,
+    #[strum(serialize = "Invalid audit log filter")]
+    ErrorInvalidFilter,
+    #[strum(serialize = "Revision not found")]
+    ErrorRevisionNotFound,
+    // Holds the current value of what was changed, if there is one to show
+    #[strum(serialize = "The account was changed by someone else in the meantime")]
+    ErrorConflict(String),
 }
 
 impl std::error::Error for ErrorMessage {}
@@ -0,0 +1,44 @@
+use core::fmt;
+use std::str::FromStr;
+
+use serde::{Deserialize, Serialize};
+
+use crate::Validator;
+
+#[derive(Debug, Clone, Serialize, Deserialize, Eq, Hash, PartialEq)]
+#[repr(transparent)]
+pub struct AuthCode(String);
+
+impl std::ops::Deref for AuthCode {
+    type Target = String;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+#[derive(Debug)]
+pub struct AuthCodeError;
+
+impl FromStr for AuthCode {
+    type Err = AuthCodeError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match Validator::validate_auth_code(s) {
+            Ok(_) => Ok(Self(s.to_string())),
+            Err(_) => Err(AuthCodeError),
+        }
+    }
+}
+
+impl Default for AuthCode {
+    fn default() -> Self {
+        AuthCode("000000".to_string())
+    }
+}
+
+impl fmt::Display for AuthCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
@@ -1,11 +1,13 @@
 #[macro_use]
 extern crate log;
 
+mod auth_code;
 mod password;
 mod phone_number;
 mod username;
 mod validator;
 
+pub use auth_code::AuthCode;
 pub use password::Password;
 pub use phone_number::PhoneNumber;
 pub use username::Username;
@@ -3,6 +3,9 @@ use regex::Regex;
 
 use utils::ErrorMessage;
 
+/// Characters a password must contain at least one of
+pub const PASSWORD_SPECIAL_CHARS: &str = "#?!@$ %&*^-+./\\";
+
 lazy_static! {
     // Email validation
     static ref USERNAME_RULE: Regex = Regex::new(r"^[a-zA-Z0-9_-]{3,20}$").unwrap();
@@ -12,10 +15,14 @@ lazy_static! {
     static ref PW_UPPER_RULE: Regex = Regex::new(r"[[:upper:]]").unwrap();
     static ref PW_LOWER_RULE: Regex = Regex::new(r"[[:lower:]]").unwrap();
     static ref PW_DIGIT_RULE: Regex = Regex::new(r"[[:digit:]]").unwrap();
-    static ref PW_SPECIAL_RULE: Regex = Regex::new(r"[#?!@$ %&\*\^\-\+\./\\]").unwrap();
+    static ref PW_SPECIAL_RULE: Regex =
+        Regex::new(&format!("[{}]", regex::escape(PASSWORD_SPECIAL_CHARS))).unwrap();
 
     // Phone number validation
     static ref PHONE_NUMBER_RULE: Regex = Regex::new(r"^[0-9]{3}-[0-9]{3}-[0-9]{4}$").unwrap();
+
+    // Second factor validation, TOTP or recovery code
+    static ref AUTH_CODE_RULE: Regex = Regex::new(r"^([0-9]{6}|[a-z0-9]{5}-[a-z0-9]{5})$").unwrap();
 }
 
 pub struct Validator;
@@ -55,4 +62,14 @@ impl Validator {
             Err(ErrorMessage::InvalidPhoneNumber)
         }
     }
+
+    pub fn validate_auth_code(code: &str) -> Result<(), ErrorMessage> {
+        if AUTH_CODE_RULE.is_match(code) {
+            debug!("Authentication code is well formed");
+            Ok(())
+        } else {
+            warn!("Authentication code is malformed");
+            Err(ErrorMessage::ErrorInvalidCode)
+        }
+    }
 }